@@ -0,0 +1,166 @@
+use axum::{
+    extract::multipart::MultipartError,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use pdfium_render::prelude::{PdfiumError, PdfiumInternalError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use utoipa::openapi::{ContentBuilder, Ref, RefOr, ResponseBuilder};
+use utoipa::{openapi, IntoResponses, ToSchema};
+
+// everything that can go wrong while handling a request, each variant maps to its own status code
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidMultipart(MultipartError),
+    MissingFile,
+    EmptyFile,
+    NotAPdf,
+    UnsupportedContentType(String),
+    FetchFailed(String),
+    PayloadTooLarge(String),
+    InvalidParameter(String),
+    InvalidPdf(PdfiumError),
+    WrongPassword,
+    PageNotFound {
+        page_index: usize,
+        page_count: usize,
+    },
+    RenderFailed(String),
+    PdfiumUnavailable,
+    Internal(String),
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidMultipart(err) => write!(f, "invalid multipart body: {}", err),
+            ApiError::MissingFile => write!(f, "no PDF file provided"),
+            ApiError::EmptyFile => write!(f, "the uploaded PDF file is empty"),
+            ApiError::NotAPdf => write!(f, "the uploaded file is not a PDF"),
+            ApiError::UnsupportedContentType(message) => write!(f, "{}", message),
+            ApiError::FetchFailed(message) => write!(f, "{}", message),
+            ApiError::PayloadTooLarge(message) => write!(f, "{}", message),
+            ApiError::InvalidParameter(message) => write!(f, "{}", message),
+            ApiError::InvalidPdf(err) => write!(f, "could not load the PDF: {:?}", err),
+            ApiError::WrongPassword => write!(f, "the PDF password is missing or incorrect"),
+            ApiError::PageNotFound {
+                page_index,
+                page_count,
+            } => write!(
+                f,
+                "page {} does not exist, the document has {} pages",
+                page_index, page_count
+            ),
+            ApiError::RenderFailed(message) => write!(f, "failed to render the PDF: {}", message),
+            ApiError::PdfiumUnavailable => write!(f, "the pdfium library is not available"),
+            ApiError::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl ApiError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            // 413 when the body goes past the DefaultBodyLimit of the router, 400 for a malformed one
+            ApiError::InvalidMultipart(err) => err.status(),
+            ApiError::MissingFile
+            | ApiError::EmptyFile
+            | ApiError::InvalidParameter(_)
+            | ApiError::InvalidPdf(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotAPdf | ApiError::UnsupportedContentType(_) => {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            }
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::WrongPassword => StatusCode::UNAUTHORIZED,
+            ApiError::PageNotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::FetchFailed(_) => StatusCode::BAD_GATEWAY,
+            ApiError::PdfiumUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::RenderFailed(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// the error responses of the endpoints taking a PDF as they show up in the openapi spec, all of
+// them an ErrorResponse
+impl IntoResponses for ApiError {
+    fn responses() -> BTreeMap<String, RefOr<openapi::Response>> {
+        [
+            (StatusCode::BAD_REQUEST, "Invalid parameter, form or PDF"),
+            (StatusCode::UNAUTHORIZED, "Missing or wrong password"),
+            (
+                StatusCode::NOT_FOUND,
+                "The page is past the end of the document",
+            ),
+            (StatusCode::PAYLOAD_TOO_LARGE, "Upload too large"),
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, "Not a PDF"),
+            (StatusCode::INTERNAL_SERVER_ERROR, "Rendering failed"),
+            (
+                StatusCode::BAD_GATEWAY,
+                "The PDF could not be fetched from its url",
+            ),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The pdfium library is not bound yet",
+            ),
+        ]
+        .into_iter()
+        .map(|(status, description)| {
+            let content = ContentBuilder::new()
+                .schema(Some(Ref::from_schema_name("ErrorResponse")))
+                .build();
+            let response = ResponseBuilder::new()
+                .description(description)
+                .content("application/json", content)
+                .build();
+            (status.as_str().to_string(), RefOr::T(response))
+        })
+        .collect()
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        // logged within the span of the request, so along with its request id
+        if self.status_code().is_server_error() {
+            tracing::error!(error = %self, "the request failed");
+        } else {
+            tracing::warn!(error = %self, "the request was rejected");
+        }
+        let body = Json(ErrorResponse {
+            error: self.to_string(),
+        });
+        (self.status_code(), body).into_response()
+    }
+}
+
+impl From<MultipartError> for ApiError {
+    fn from(err: MultipartError) -> Self {
+        ApiError::InvalidMultipart(err)
+    }
+}
+
+// pdfium failing on a document it already loaded means the page couldn't be processed
+impl From<PdfiumError> for ApiError {
+    fn from(err: PdfiumError) -> Self {
+        ApiError::RenderFailed(format!("{:?}", err))
+    }
+}
+
+// pdfium reports a missing or wrong password with its own error code, anything else means
+// the uploaded file is not a PDF it can read
+pub fn load_error(err: PdfiumError) -> ApiError {
+    match err {
+        PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::PasswordError) => {
+            ApiError::WrongPassword
+        }
+        err => ApiError::InvalidPdf(err),
+    }
+}
@@ -0,0 +1,296 @@
+// PDFs given by url instead of uploaded, fetched over https and then handled like an upload
+use crate::error::ApiError;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{header, redirect, Client, Url};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+// largest PDF fetched from a url when MAX_FETCH_SIZE_MB is not set, the same as a batch document
+const DEFAULT_MAX_FETCH_SIZE: usize = 100 * 1024 * 1024;
+static MAX_FETCH_SIZE: OnceLock<usize> = OnceLock::new();
+// for the whole fetch, from connecting to the last byte of the body
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+
+// sets the largest PDF that can be fetched from a url, only the first call counts
+pub fn set_max_fetch_size(max_fetch_size: usize) {
+    let _ = MAX_FETCH_SIZE.set(max_fetch_size);
+}
+
+fn max_fetch_size() -> usize {
+    *MAX_FETCH_SIZE.get_or_init(|| DEFAULT_MAX_FETCH_SIZE)
+}
+
+// loopback, private, link-local and unspecified addresses, and the unique local ipv6 range. A url
+// pointing there would have the server fetch from its own network (metadata endpoints, admin ports,
+// ...) on behalf of the client. Ipv4 addresses mapped into ipv6 are checked as ipv4
+fn is_internal_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal_ipv4(ip),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // fc00::/7, unique local addresses
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    // fe80::/10, link-local addresses
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+fn is_internal_ipv4(ip: Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+}
+
+// the error a url to an internal address fails with, told apart from the other fetch errors so
+// it's reported as a bad url rather than as an unreachable server
+#[derive(Debug)]
+struct InternalAddress(String);
+
+impl fmt::Display for InternalAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} points to an internal address", self.0)
+    }
+}
+
+impl Error for InternalAddress {}
+
+// the ip addresses written in the url, ipv6 ones between brackets. Url parsing already turned the
+// other ipv4 notations (`2130706433`, `0x7f.1`, ...) into dotted ones
+fn check_host(url: &Url) -> Result<(), InternalAddress> {
+    let host = url.host_str().unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) if is_internal_address(ip) => Err(InternalAddress(url.to_string())),
+        _ => Ok(()),
+    }
+}
+
+// resolves the host names of the fetched urls and refuses the ones resolving to an internal address,
+// checked on every connection so a redirect or a second dns answer can't get around it. Ip literals
+// are never resolved, check_host covers them
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addresses = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+            if addresses
+                .iter()
+                .any(|address| is_internal_address(address.ip()))
+            {
+                return Err(InternalAddress(host).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+// shared by every fetch so the connections and tls sessions are reused. Redirects are followed as
+// long as they stay on https and off the internal addresses. No proxy is used, it would resolve the
+// host names itself and get around PublicResolver
+fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .https_only(true)
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if attempt.url().scheme() != "https" {
+                    attempt.error("redirected away from https")
+                } else if let Err(err) = check_host(attempt.url()) {
+                    attempt.error(err)
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()
+            .expect("failed to build the http client")
+    })
+}
+
+// only https urls to a public address are fetched. The host names are checked once resolved, by
+// the resolver of the client
+fn parse_pdf_url(value: &str) -> Result<Url, ApiError> {
+    let url = Url::parse(value.trim())
+        .map_err(|err| ApiError::InvalidParameter(format!("invalid url `{}`: {}", value, err)))?;
+    if url.scheme() != "https" {
+        return Err(ApiError::InvalidParameter(format!(
+            "only https urls can be fetched, got `{}`",
+            value
+        )));
+    }
+    check_host(&url).map_err(|err| ApiError::InvalidParameter(err.to_string()))?;
+    Ok(url)
+}
+
+// a request refused because it led to an internal address is the client's fault, a 400, anything
+// else is a server that couldn't be fetched from
+fn fetch_error(message: &str, url: &Url, err: reqwest::Error) -> ApiError {
+    let mut source = err.source();
+    while let Some(cause) = source {
+        if let Some(internal_address) = cause.downcast_ref::<InternalAddress>() {
+            return ApiError::InvalidParameter(internal_address.to_string());
+        }
+        source = cause.source();
+    }
+    ApiError::FetchFailed(format!("{} {} failed: {}", message, url, err))
+}
+
+// `application/pdf`, whatever the case and the parameters, e.g. `application/pdf; qs=0.001`
+fn is_pdf_content_type(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .is_some_and(|mime_type| mime_type.trim().eq_ignore_ascii_case("application/pdf"))
+}
+
+// downloads the PDF at `url`, at most `max_size` bytes (MAX_FETCH_SIZE_MB when it's larger or missing).
+// The body is read chunk by chunk so a server lying about its Content-Length, or not sending one,
+// can't make it buffer more than that
+pub async fn fetch_pdf(url: &str, max_size: Option<usize>) -> Result<Vec<u8>, ApiError> {
+    let url = parse_pdf_url(url)?;
+    let max_size = max_size.map_or(max_fetch_size(), |max_size| max_size.min(max_fetch_size()));
+    let too_large = || {
+        ApiError::PayloadTooLarge(format!(
+            "the PDF at {} is larger than the {} bytes allowed",
+            url, max_size
+        ))
+    };
+
+    let mut response = client()
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| fetch_error("fetching", &url, err))?;
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+    if !is_pdf_content_type(content_type) {
+        return Err(ApiError::UnsupportedContentType(format!(
+            "{} is served as `{}` instead of application/pdf",
+            url, content_type
+        )));
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_size as u64)
+    {
+        return Err(too_large());
+    }
+
+    let mut pdf_data = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|err| fetch_error("reading", &url, err))?
+    {
+        if pdf_data.len() + chunk.len() > max_size {
+            return Err(too_large());
+        }
+        pdf_data.extend_from_slice(&chunk);
+    }
+    Ok(pdf_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pdf_url_accepts_https_urls() {
+        let url = parse_pdf_url(" https://example.com/files/report.pdf?version=2 ").unwrap();
+        assert_eq!(url.host_str(), Some("example.com"));
+    }
+
+    #[test]
+    fn parse_pdf_url_rejects_anything_but_https() {
+        for url in [
+            "http://example.com/report.pdf",
+            "http://169.254.169.254/latest/meta-data/",
+            "file:///etc/passwd",
+            "ftp://example.com/report.pdf",
+            "example.com/report.pdf",
+            "",
+        ] {
+            assert!(
+                matches!(parse_pdf_url(url), Err(ApiError::InvalidParameter(_))),
+                "{} was accepted",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn parse_pdf_url_rejects_internal_addresses() {
+        for url in [
+            "https://127.0.0.1/x.pdf",
+            "https://2130706433/x.pdf",
+            "https://10.1.2.3/x.pdf",
+            "https://172.16.0.1/x.pdf",
+            "https://172.31.255.255/x.pdf",
+            "https://192.168.1.1/x.pdf",
+            "https://169.254.169.254/latest/meta-data/",
+            "https://0.0.0.0/x.pdf",
+            "https://[::1]/x.pdf",
+            "https://[::]/x.pdf",
+            "https://[fd00::1]/x.pdf",
+            "https://[fe80::1]/x.pdf",
+            "https://[::ffff:127.0.0.1]/x.pdf",
+        ] {
+            assert!(
+                matches!(parse_pdf_url(url), Err(ApiError::InvalidParameter(_))),
+                "{} was accepted",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn parse_pdf_url_accepts_public_addresses() {
+        for url in [
+            "https://93.184.215.14/x.pdf",
+            "https://172.32.0.1/x.pdf",
+            "https://[2606:2800:21f:cb07:6820:80da:af6b:8b2c]/x.pdf",
+        ] {
+            assert!(parse_pdf_url(url).is_ok(), "{} was rejected", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_pdf_rejects_host_names_resolving_to_internal_addresses() {
+        let err = fetch_pdf("https://localhost/x.pdf", None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, ApiError::InvalidParameter(ref message) if message.contains("internal address")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn is_pdf_content_type_ignores_the_case_and_the_parameters() {
+        assert!(is_pdf_content_type("application/pdf"));
+        assert!(is_pdf_content_type("Application/PDF; qs=0.001"));
+        assert!(!is_pdf_content_type("text/html; charset=utf-8"));
+        assert!(!is_pdf_content_type("application/pdfx"));
+        assert!(!is_pdf_content_type(""));
+    }
+}
@@ -0,0 +1,139 @@
+// the hyperlinks of a page and where they lead
+use crate::text::{PageTransform, RectResponse};
+use pdfium_render::prelude::*;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+// where a link leads, `{"type": "web", "uri": "..."}` or `{"type": "internal", "page_index": 3}`
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LinkTarget {
+    Web { uri: String },
+    Internal { page_index: usize },
+}
+
+// a clickable area of the page, in the same top left based coordinates as the svg
+#[derive(Serialize, ToSchema)]
+pub struct LinkInfo {
+    #[serde(flatten)]
+    pub target: LinkTarget,
+    pub rect: RectResponse,
+}
+
+// named destinations are resolved by pdfium, the destination can also hide behind a goto action
+pub fn destination_page_index(
+    destination: Option<PdfDestination<'_>>,
+    action: Option<PdfAction<'_>>,
+) -> Option<usize> {
+    let page_index = match destination {
+        Some(destination) => destination.page_index().ok(),
+        None => action.and_then(|action| {
+            action
+                .as_local_destination_action()
+                .and_then(|action| action.destination().ok())
+                .and_then(|destination| destination.page_index().ok())
+        }),
+    };
+    page_index.map(|page_index| page_index as usize)
+}
+
+// the web and internal links of the page, links launching files or pointing to other documents are
+// left out along with the ones pdfium can't read
+pub fn extract_page_links(page: &PdfPage<'_>, transform: &PageTransform) -> Vec<LinkInfo> {
+    page.links()
+        .iter()
+        .filter_map(|link| {
+            let action = link.action();
+            let uri = action
+                .as_ref()
+                .and_then(|action| action.as_uri_action())
+                .and_then(|action| action.uri().ok());
+            let target = match uri {
+                Some(uri) => LinkTarget::Web { uri },
+                None => LinkTarget::Internal {
+                    page_index: destination_page_index(link.destination(), action)?,
+                },
+            };
+            let (x, y, width, height) = transform.rect_to_display(&link.rect().ok()?);
+            Some(LinkInfo {
+                target,
+                rect: RectResponse {
+                    x,
+                    y,
+                    width,
+                    height,
+                },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two 200 by 100 points pages, the first one links to https://example.com/ from its top left
+    // corner and to the second page from its bottom right corner
+    const LINKS_PDF: &[u8] = b"%PDF-1.4\n\
+1 0 obj\n\
+<</Type/Catalog/Pages 2 0 R>>\n\
+endobj\n\
+2 0 obj\n\
+<</Type/Pages/Kids[3 0 R 4 0 R]/Count 2>>\n\
+endobj\n\
+3 0 obj\n\
+<</Type/Page/Parent 2 0 R/MediaBox[0 0 200 100]/Annots[5 0 R 6 0 R]>>\n\
+endobj\n\
+4 0 obj\n\
+<</Type/Page/Parent 2 0 R/MediaBox[0 0 200 100]>>\n\
+endobj\n\
+5 0 obj\n\
+<</Type/Annot/Subtype/Link/Rect[10 60 90 80]/A<</S/URI/URI(https://example.com/)>>>>\n\
+endobj\n\
+6 0 obj\n\
+<</Type/Annot/Subtype/Link/Rect[110 10 190 30]/A<</S/GoTo/D[4 0 R/Fit]>>>>\n\
+endobj\n\
+xref\n\
+0 7\n\
+0000000000 65535 f \n\
+0000000009 00000 n \n\
+0000000054 00000 n \n\
+0000000111 00000 n \n\
+0000000196 00000 n \n\
+0000000261 00000 n \n\
+0000000361 00000 n \n\
+trailer\n\
+<</Size 7/Root 1 0 R>>\n\
+startxref\n\
+451\n\
+%%EOF\n";
+
+    #[test]
+    fn extract_page_links_tells_web_and_internal_links_apart() {
+        let Some(pdfium) = crate::test_pdfium() else {
+            return;
+        };
+        let pdfium = pdfium.lock().unwrap();
+        let document = pdfium.load_pdf_from_byte_slice(LINKS_PDF, None).unwrap();
+        let page = document.pages().get(0).unwrap();
+        let links = extract_page_links(&page, &PageTransform::new(&page).unwrap());
+        assert_eq!(
+            serde_json::to_value(links).unwrap(),
+            serde_json::json!([
+                {
+                    "type": "web",
+                    "uri": "https://example.com/",
+                    "rect": { "x": 10.0, "y": 20.0, "width": 80.0, "height": 20.0 }
+                },
+                {
+                    "type": "internal",
+                    "page_index": 1,
+                    "rect": { "x": 110.0, "y": 70.0, "width": 80.0, "height": 20.0 }
+                },
+            ])
+        );
+
+        let page = document.pages().get(1).unwrap();
+        assert!(extract_page_links(&page, &PageTransform::new(&page).unwrap()).is_empty());
+    }
+}
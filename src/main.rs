@@ -1,282 +1,229 @@
-use axum::{
-    body::Body,
-    extract::{DefaultBodyLimit, Multipart, Query},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::post,
-    Router,
-};
+mod error;
+mod fetch;
+mod links;
+mod openapi;
+mod render;
+mod render_cache;
+mod server;
+mod svg;
+mod text;
+mod zip;
+
+use crate::render_cache::RenderCache;
+use crate::server::AppState;
+use axum::http::{header, HeaderName, HeaderValue, Method};
 use pdfium_render::prelude::*;
-use regex::Regex;
-use std::collections::HashMap;
-use std::fmt::Write;
-use std::io::Cursor;
+use std::env;
+use std::num::NonZeroUsize;
+use std::path::{Path as StdPath, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+// address the server listens on when BIND_ADDR is not set
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:1234";
+// directory holding the pdfium shared library when PDFIUM_LIBRARY_PATH is not set
+const DEFAULT_PDFIUM_LIB_PATH: &str = "./pdfium";
+// number of encoded page images kept in memory when PDFIUM_CACHE_SIZE is not set
+const DEFAULT_CACHE_SIZE: usize = 256;
+// times binding the pdfium library is retried when PDFIUM_BIND_RETRIES is not set
+const DEFAULT_BIND_RETRIES: u32 = 5;
+// wait before the first retry, doubled after every failed attempt
+const BIND_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// lets browsers call the api from other origins, CORS_ALLOWED_ORIGINS is a comma separated list of
+// origins (e.g. `https://app.example.com,https://admin.example.com`), any origin is allowed by default
+fn cors_layer() -> CorsLayer {
+    let allow_origin = match env::var("CORS_ALLOWED_ORIGINS") {
+        Ok(origins) if origins.trim() != "*" => {
+            AllowOrigin::list(origins.split(',').map(|origin| {
+                HeaderValue::from_str(origin.trim())
+                    .expect("CORS_ALLOWED_ORIGINS must be a comma separated list of origins")
+            }))
+        }
+        _ => AllowOrigin::any(),
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+        // the timing headers are otherwise hidden from scripts
+        .expose_headers([
+            HeaderName::from_static(server::EXTRACT_MS_HEADER),
+            HeaderName::from_static(server::RENDER_MS_HEADER),
+            HeaderName::from_static(server::REQUEST_ID_HEADER),
+        ])
+}
 
-#[derive(Clone)]
-struct GeneratedRect {
-    lx_pos: Vec<f32>,
-    ly_pos: Vec<f32>,
-    text: String,
-    font_family: String,
-    right: f32,
-    font_size: f32,
+// binds the pdfium library, retrying with an exponential backoff since in containers the library can
+// be mounted by a sidecar after the process started. PDFIUM_BIND_RETRIES sets how many times
+async fn bind_pdfium_with_retries() -> Option<Pdfium> {
+    let retries = match env::var("PDFIUM_BIND_RETRIES") {
+        Ok(retries) => retries
+            .parse::<u32>()
+            .expect("PDFIUM_BIND_RETRIES must be a number"),
+        Err(_) => DEFAULT_BIND_RETRIES,
+    };
+    let mut delay = BIND_RETRY_DELAY;
+    for attempt in 0..=retries {
+        if let Some(pdfium) = bind_pdfium() {
+            return Some(pdfium);
+        }
+        if attempt < retries {
+            tracing::warn!(
+                attempt = attempt + 1,
+                retries,
+                "binding the pdfium library failed, retrying in {}s",
+                delay.as_secs()
+            );
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    None
 }
 
-struct PageImage {
-    scale: f32,
-    buffer: Vec<u8>,
+// binds the pdfium library once, logging why it failed
+fn bind_pdfium() -> Option<Pdfium> {
+    // PDFIUM_LIBRARY_PATH (or PDFIUM_LIB_PATH) is either the library itself or the directory holding it,
+    // otherwise fall back to the bundled ./pdfium directory
+    let pdfium_lib_path = env::var("PDFIUM_LIBRARY_PATH")
+        .or_else(|_| env::var("PDFIUM_LIB_PATH"))
+        .unwrap_or_else(|_| DEFAULT_PDFIUM_LIB_PATH.to_string());
+    let library_path = if StdPath::new(&pdfium_lib_path).is_dir() {
+        Pdfium::pdfium_platform_library_name_at_path(&pdfium_lib_path)
+    } else {
+        PathBuf::from(&pdfium_lib_path)
+    };
+
+    if !library_path.is_file() {
+        tracing::error!(
+            "the pdfium library was not found at {}, set PDFIUM_LIBRARY_PATH to its location",
+            library_path.display()
+        );
+        return None;
+    }
+    match Pdfium::bind_to_library(&library_path) {
+        Ok(bindings) => Some(Pdfium::new(bindings)),
+        Err(err) => {
+            tracing::error!(
+                "failed to bind to the pdfium library at {}: {:?}",
+                library_path.display(),
+                err
+            );
+            None
+        }
+    }
 }
 
-// TODO: do we need the full text as a string?
-struct PagePayload {
-    svg_text: String,
-    images: Vec<PageImage>,
+// the pdfium library for the tests that need it, bound once for the whole test run. Those tests
+// return early when the library can't be found
+#[cfg(test)]
+fn test_pdfium() -> Option<Arc<Mutex<Pdfium>>> {
+    static PDFIUM: std::sync::OnceLock<Option<Arc<Mutex<Pdfium>>>> = std::sync::OnceLock::new();
+    PDFIUM
+        .get_or_init(|| bind_pdfium().map(|pdfium| Arc::new(Mutex::new(pdfium))))
+        .clone()
 }
 
 #[tokio::main]
 async fn main() {
-    let app = Router::new()
-        .route("/process", post(process_pdf))
-        .layer(DefaultBodyLimit::max(250 * 1024 * 1024));
-
-    // Run the server
-    // run our app with hyper, listening globally on port 1234
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:1234").await.unwrap();
-    axum::serve(listener, app).await.unwrap()
-}
-
-async fn process_pdf(
-    Query(params): Query<HashMap<String, String>>,
-    mut multipart: Multipart,
-) -> impl IntoResponse {
-    // Extract the boolean which represents if we are dealing with a main book or with an answer book
-    let is_answer_book: bool = match params
-        .get("answer_book")
-        .and_then(|p| p.parse::<usize>().ok())
-    {
-        Some(value) => value != 0,
-        None => false,
+    // json logs, one line per event plus one when each span closes with the time spent in it. The
+    // level is set with RUST_LOG (e.g. `RUST_LOG=debug` or `RUST_LOG=rust_pdf=debug,info`), info by default
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+
+    // PDFIUM_CACHE_SIZE is the number of encoded page images kept in memory, 0 disables the cache
+    let cache_size = match env::var("PDFIUM_CACHE_SIZE") {
+        Ok(cache_size) => cache_size
+            .parse::<usize>()
+            .expect("PDFIUM_CACHE_SIZE must be a number"),
+        Err(_) => DEFAULT_CACHE_SIZE,
+    };
+    let state = AppState {
+        pdfium: Arc::new(OnceLock::new()),
+        render_cache: NonZeroUsize::new(cache_size)
+            .map(|capacity| Arc::new(Mutex::new(RenderCache::new(capacity)))),
     };
 
-    // Extract the PDF file from the multipart form
-    let mut pdf_data: Option<Vec<u8>> = None;
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let data = field
-            .bytes()
-            .await
-            .map_err(|_| StatusCode::BAD_REQUEST)
-            .unwrap();
-        pdf_data = Some(data.to_vec());
+    // MAX_RENDER_MEGAPIXELS caps the size of every rendered bitmap, larger renders fail with a 400
+    if let Ok(max_megapixels) = env::var("MAX_RENDER_MEGAPIXELS") {
+        let max_megapixels = max_megapixels
+            .parse::<f64>()
+            .ok()
+            .filter(|max_megapixels| *max_megapixels > 0.0)
+            .expect("MAX_RENDER_MEGAPIXELS must be a positive number");
+        render::set_max_render_pixels((max_megapixels * 1_000_000.0) as u64);
     }
-    let pdf_data = pdf_data.unwrap();
-
-    // Create a new Pdfium instance for this request
-    let pdfium = Pdfium::new(
-        Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path("./pdfium"))
-            .map_err(|_| StatusCode::BAD_REQUEST)
-            .unwrap(),
-    );
-
-    // Load the PDF document
-    let document = pdfium
-        .load_pdf_from_byte_vec(pdf_data, None)
-        .map_err(|_| StatusCode::BAD_REQUEST)
-        .unwrap();
 
-    let mut pages_payload: Vec<PagePayload> = Vec::new();
-
-    // Iterate over the document's pages to parse the text & generate the images
-    for (_u, page) in document.pages().iter().enumerate() {
-        let page_ref = &page;
-        // Get page size info
-        let page_width = page_ref.width().value;
-        let page_height = page_ref.height().value;
-
-        // Parse the page for the text & generate svg string
-        let text_group_rects = extract_page_text_groups(page_ref, page_height);
-        let svg_text = get_string_from_rects(page_width, page_height, text_group_rects);
-
-        // Generate the images
-        let page_images = generate_page_images(page_ref, page_width, page_height, is_answer_book);
-
-        pages_payload.push(PagePayload {
-            svg_text,
-            images: page_images,
-        })
+    // MAX_FETCH_SIZE_MB caps the size of the PDFs fetched from a `url`, 100 by default
+    if let Ok(max_fetch_size_mb) = env::var("MAX_FETCH_SIZE_MB") {
+        let max_fetch_size_mb = max_fetch_size_mb
+            .parse::<usize>()
+            .ok()
+            .filter(|max_fetch_size_mb| *max_fetch_size_mb > 0)
+            .expect("MAX_FETCH_SIZE_MB must be a positive number");
+        fetch::set_max_fetch_size(max_fetch_size_mb.saturating_mul(1024 * 1024));
     }
 
-    print!("{}", pages_payload[0].svg_text);
-
-    // Send over payload
-    // TODO: figure out how to send the actual payload
-    // let body = Body::from(pages_payload[0].svg_text.clone()).into_response();
-    let body = Body::from(pages_payload[0].images[2].buffer.clone()).into_response();
-    return body;
-}
-
-// returns the svg string from the generated text rects
-fn get_string_from_rects(page_width: f32, page_height: f32, rects: Vec<GeneratedRect>) -> String {
-    if rects.is_empty() {
-        return String::new();
-    }
-
-    let mut svg_content = format!(
-        r#"<svg 
-        xmlns="http://www.w3.org/2000/svg" 
-        width="{page_width}" 
-        height="{page_height}" 
-        viewBox="0 0 {page_width} {page_height}" 
-        style="font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif; text-rendering: optimizeLegibility; shape-rendering: geometricPrecision"><title>text-layer</title>"#,
-        page_width = page_width,
-        page_height = page_height
-    );
-
-    for rect in rects {
-        // Add text element with orientation-aware styling
-        let _ = write!(
-            svg_content,
-            r#"<text 
-            style="font-size:{font_size}pt; white-space: pre; text-rendering: geometricPrecision; dominant-baseline: hanging; font-weight: 400; letter-spacing: -0.01em; fill: rgb(230, 179, 179);">"#,
-            font_size = rect.font_size,
-        );
-
-        let _ = write!(
-            svg_content,
-            r#"<tspan x="{primary_value}" y="{secondary_value}">{text}</tspan></text>"#,
-            primary_value = rect
-                .lx_pos
-                .iter()
-                .map(|num| num.to_string())
-                .collect::<Vec<String>>()
-                .join(" "),
-            secondary_value = rect
-                .ly_pos
-                .iter()
-                .map(|num| num.to_string())
-                .collect::<Vec<String>>()
-                .join(" "),
-            text = rect.text
-        );
+    // PDFIUM_WORKERS sizes the pool encoding the page images, by default there is one thread per core
+    if let Ok(workers) = env::var("PDFIUM_WORKERS") {
+        let workers = workers
+            .parse::<usize>()
+            .ok()
+            .filter(|workers| *workers > 0)
+            .expect("PDFIUM_WORKERS must be a positive number");
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(workers)
+            .build_global()
+            .expect("failed to build the worker pool");
     }
 
-    svg_content.push_str("</svg>");
-    svg_content
-}
-
-// calculated manually by iterating over the chars to get their absolute origin and grouped by closeness & font size
-// returns the text boxes for this page
-// TODO: for certain text it gets cut off when printing it
-fn extract_page_text_groups(page: &PdfPage<'_>, page_height: f32) -> Vec<GeneratedRect> {
-    let re = Regex::new(r"/[\x00-\x08\x0B-\x0C\x0E-\x1F\x7F]|\r|\n/").unwrap();
-
-    let text = page.text().unwrap();
-    let chars: PdfPageTextChars = text.chars();
-
-    let mut groups: Vec<GeneratedRect> = Vec::new();
-    let mut current_group: Option<GeneratedRect> = None;
-
-    for (_index, char) in chars.iter().enumerate() {
-        let curr = char.unicode_string().unwrap();
-        let font_family = char.font_name();
-        let char_origin_x = char.origin_x().unwrap().value;
-        let mut char_origin_y = char.origin_y().unwrap().value;
-        let loose_bounds = char.loose_bounds().unwrap();
-
-        // fix up y coordinates due to different origin
-        char_origin_y = page_height - char_origin_y;
-
-        // skip the iteration if the char is outside the page, if the current char is not printable or if its height is 0.0
-        if char_origin_x < 0.0
-            || char_origin_y < 0.0
-            || re.is_match(&curr)
-            || loose_bounds.height().value == 0.0
-        {
-            continue;
-        }
-
-        // Use `ref mut` to get a mutable reference to `current_group` directly
-        if let Some(ref mut unwrapped_current_group) = current_group {
-            let is_close_enough = (loose_bounds.left.value - unwrapped_current_group.right).abs()
-                > loose_bounds.width().value + 5.0;
-            let is_new_group =
-                unwrapped_current_group.font_family != font_family || is_close_enough;
-
-            if is_new_group {
-                groups.push(unwrapped_current_group.clone());
-                current_group = Some(GeneratedRect {
-                    lx_pos: vec![char_origin_x],
-                    ly_pos: vec![char_origin_y - loose_bounds.height().value],
-                    text: curr.clone(),
-                    font_family: font_family.clone(),
-                    right: loose_bounds.right.value,
-                    font_size: loose_bounds.height().value,
-                });
-            } else {
-                unwrapped_current_group.font_size = unwrapped_current_group
-                    .font_size
-                    .max(loose_bounds.height().value);
-                unwrapped_current_group.lx_pos.push(char_origin_x);
-                unwrapped_current_group
-                    .ly_pos
-                    .push(char_origin_y - unwrapped_current_group.font_size);
-                unwrapped_current_group.text.push_str(&curr);
-                unwrapped_current_group.right = loose_bounds.right.value;
+    // the server answers while the library is being bound, /ready stays at 503 until it is. Nothing
+    // works without pdfium though, give up once the retries are exhausted
+    let pdfium = state.pdfium.clone();
+    tokio::spawn(async move {
+        match bind_pdfium_with_retries().await {
+            Some(bound) => {
+                let _ = pdfium.set(Arc::new(Mutex::new(bound)));
+                tracing::info!("the pdfium library is bound");
+            }
+            None => {
+                tracing::error!("could not bind the pdfium library, exiting");
+                process::exit(1);
             }
-        } else {
-            // Handle the case where `current_group` is `None`
-            current_group = Some(GeneratedRect {
-                lx_pos: vec![char_origin_x],
-                ly_pos: vec![char_origin_y - loose_bounds.height().value],
-                text: curr.clone(),
-                font_family: font_family.clone(),
-                right: loose_bounds.right.value,
-                font_size: loose_bounds.height().value,
-            });
         }
-    }
+    });
 
-    if current_group.is_some() {
-        groups.push(current_group.unwrap().clone());
-    }
-    return groups;
-}
+    // the preflight OPTIONS requests are answered by the cors layer itself
+    let app = server::router(state).layer(cors_layer());
 
-// function to return the images as buffers at specific scales
-fn generate_page_images(
-    page: &PdfPage<'_>,
-    page_width: f32,
-    page_height: f32,
-    with_transparency: bool,
-) -> Vec<PageImage> {
-    let mut result: Vec<PageImage> = Vec::new();
-    let mut color: PdfColor = PdfColor::WHITE;
-    if with_transparency {
-        color = color.with_alpha(0);
+    // Run the server
+    // run our app with hyper, listening on BIND_ADDR (globally on port 1234 by default)
+    let bind_addr = env::var("BIND_ADDR").unwrap_or_else(|_| DEFAULT_BIND_ADDR.to_string());
+    let listener = match tokio::net::TcpListener::bind(&bind_addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("failed to bind to {}: {}", bind_addr, err);
+            process::exit(1);
+        }
+    };
+    match listener.local_addr() {
+        Ok(local_addr) => tracing::info!("listening on {}", local_addr),
+        Err(_) => tracing::info!("listening on {}", bind_addr),
     }
-    // TODO: define which scales you want
-    let scales: Vec<f32> = vec![0.25, 0.5, 1.0, 1.5, 2.0];
-    for (_i, scale) in scales.iter().enumerate() {
-        let render_config = PdfRenderConfig::new()
-            .set_format(PdfBitmapFormat::BGRA)
-            .set_reverse_byte_order(true)
-            .set_clear_color(color)
-            .set_target_size((page_width * scale) as i32, (page_height * scale) as i32);
-
-        let dynamic_image = page
-            .render_with_config(&render_config)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
-            .unwrap()
-            .as_image() // Renders this page to an image::DynamicImage
-            .into_rgba8();
-        let mut image_buffer = Vec::new();
-        dynamic_image
-            .write_to(&mut Cursor::new(&mut image_buffer), image::ImageFormat::Png)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
-            .unwrap();
-        result.push(PageImage {
-            scale: *scale,
-            buffer: image_buffer,
-        });
+    if let Err(err) = axum::serve(listener, app).await {
+        tracing::error!("the server stopped: {}", err);
+        process::exit(1);
     }
-    return result;
 }
@@ -0,0 +1,221 @@
+// the OpenAPI description served at /openapi.json and browsed at /docs. The paths and the response
+// schemas are generated from the handlers and the response structs, the query parameters are declared
+// here since the handlers read them as a map and parse them themselves (see RenderOptions::from_params).
+// Nothing builds the forms and parameters below, they only exist for their ToSchema and IntoParams
+// impls, hence their allow(dead_code)
+
+use crate::error::ErrorResponse;
+use crate::render::OutputMode;
+use crate::text::Granularity;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "rust-pdf",
+        description = "Renders PDF pages into images and a selectable svg text layer, and reads their text, links, forms and metadata. Every endpoint but the probes takes the PDF as the `file` field of a multipart form, the endpoints taking a single PDF can fetch it from an https `url` instead"
+    ),
+    tags(
+        (name = "documents", description = "Everything read or rendered from an uploaded PDF"),
+        (name = "probes", description = "Liveness and readiness of the server")
+    ),
+    // neither the error responses, described by hand (see ApiError), nor the types of the query
+    // parameters have their schemas picked up on their own
+    components(schemas(ErrorResponse, ImageFormatName, OutputMode, Granularity, SearchGroup))
+)]
+pub struct ApiDoc;
+
+// the multipart form of the endpoints taking a single PDF
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct PdfUploadForm {
+    /// The PDF, it can be left out when a `url` is given
+    #[schema(value_type = Option<String>, format = Binary)]
+    file: Option<Vec<u8>>,
+    /// Https url the PDF is fetched from when no file is uploaded, wins over the `url` query parameter
+    url: Option<String>,
+    /// Password of encrypted PDFs, wins over the `password` query parameter
+    password: Option<String>,
+}
+
+// the multipart form of /batch and /merge, every file field is a PDF
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct PdfUploadsForm {
+    #[schema(value_type = Vec<String>, format = Binary)]
+    files: Vec<Vec<u8>>,
+    /// Password of the encrypted PDFs, wins over the `password` query parameter
+    password: Option<String>,
+}
+
+// ImageFormat carries the avif speed, the query parameter is just the name of the format
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[schema(rename_all = "lowercase")]
+pub enum ImageFormatName {
+    Png,
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+// a file sent back as is, e.g. a png, a PDF or a zip archive
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[schema(value_type = String, format = Binary)]
+pub struct FileBody(Vec<u8>);
+
+#[allow(dead_code)]
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UploadParams {
+    /// Password of encrypted PDFs, it can also be sent as a `password` form field which wins over the query parameter
+    password: Option<String>,
+    /// Lower size limit of the uploaded file, in megabytes
+    #[param(minimum = 1)]
+    max_size_mb: Option<usize>,
+    /// Https url to fetch the PDF from instead of uploading it, it has to be served as application/pdf
+    #[param(example = "https://example.com/report.pdf")]
+    url: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageRangeParams {
+    /// First page to process, 0-based and inclusive
+    page_start: Option<usize>,
+    /// Last page to process, 0-based and inclusive
+    page_end: Option<usize>,
+    /// 1-based list of pages and ranges to process, wins over page_start/page_end
+    #[param(example = "1-3,5")]
+    pages: Option<String>,
+    /// Fail with a 400 instead of skipping pages of `pages` past the end of the document
+    strict: Option<bool>,
+}
+
+#[allow(dead_code)]
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageParams {
+    /// 0-based index of the page
+    page: usize,
+}
+
+#[allow(dead_code)]
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RenderParams {
+    /// Comma separated scales to render every page at, 0.25,0.5,1,1.5,2 by default (at most 8, each up to 4)
+    #[param(example = "0.5,1.0")]
+    scales: Option<String>,
+    /// Render a single image at this resolution, wins over scales
+    #[param(minimum = 10, maximum = 1200)]
+    dpi: Option<f32>,
+    /// Encoding of the images, png by default. avif needs the server to be built with the avif feature
+    format: Option<ImageFormatName>,
+    /// Encode the jpeg images as progressive jpegs, ignored by the other formats
+    progressive: Option<bool>,
+    /// Quality of the lossy formats, 85 by default
+    #[param(minimum = 1, maximum = 100)]
+    quality: Option<u8>,
+    /// AVIF encoder speed, 6 by default
+    #[param(minimum = 1, maximum = 10)]
+    speed: Option<u8>,
+    /// Render the images in shades of gray
+    grayscale: Option<bool>,
+    /// `1` renders each page once at the largest scale and resizes that image for the smaller scales. Faster with several scales, but thin lines and small text are softer than in a render of their own
+    downscale: Option<bool>,
+    /// Render on a transparent background
+    answer_book: Option<bool>,
+    /// Page background, a hex color or a css color name, wins over background_color
+    #[param(example = "lightgray")]
+    bg: Option<String>,
+    /// Page background, `#rrggbb` or `#rrggbbaa`
+    #[param(example = "#f4ecd8")]
+    background_color: Option<String>,
+    /// Left of the region to render, in points
+    #[param(minimum = 0)]
+    clip_x: Option<f32>,
+    /// Top of the region to render, in points
+    #[param(minimum = 0)]
+    clip_y: Option<f32>,
+    /// Width of the region to render, in points
+    #[param(exclusive_minimum = 0)]
+    clip_w: Option<f32>,
+    /// Height of the region to render, in points
+    #[param(exclusive_minimum = 0)]
+    clip_h: Option<f32>,
+    /// Also return the images stored in the pages
+    include_embedded_images: Option<bool>,
+    /// What gets generated for every page, `text` only returns the plain text of the pages and `zip` streams the svg and images of every page as the files of a zip archive (`/process` only)
+    output: Option<OutputMode>,
+}
+
+#[allow(dead_code)]
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TextLayerParams {
+    /// Css color of the svg text, transparent by default
+    text_fill: Option<String>,
+    /// Hex color of the svg text, or transparent, wins over text_fill
+    text_color: Option<String>,
+    /// What every text element of the svg stands for
+    granularity: Option<Granularity>,
+    /// Gap in points past which two chars of the svg text are split by a space
+    #[param(minimum = 0, maximum = 50)]
+    gap_threshold: Option<f32>,
+    /// Baseline shift, as a fraction of the font size, starting a new line
+    #[param(exclusive_minimum = 0, maximum = 2, default = 0.3)]
+    y_band_tolerance: Option<f32>,
+}
+
+#[allow(dead_code)]
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StreamParams {
+    /// Stream the pages as newline delimited json
+    stream: Option<bool>,
+    /// Send the pages as multipart/mixed parts
+    multipart: Option<bool>,
+}
+
+#[allow(dead_code)]
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ThumbnailParams {
+    /// Width of the box the thumbnail fits in, 256 by default
+    #[param(minimum = 1, maximum = 2048)]
+    max_width: Option<u32>,
+    /// Height of the box the thumbnail fits in, 256 by default
+    #[param(minimum = 1, maximum = 2048)]
+    max_height: Option<u32>,
+}
+
+#[allow(dead_code)]
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    /// Text to search for
+    q: String,
+    /// Don't ignore the case
+    case_sensitive: Option<bool>,
+    /// `page` returns `[{"page", "matches": [{"text", "x", "y", "width", "height"}]}]` instead, one entry per page with a match
+    group: Option<SearchGroup>,
+}
+
+// how the matches of `/search` are returned
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[schema(rename_all = "lowercase")]
+pub enum SearchGroup {
+    Page,
+}
+
+#[allow(dead_code)]
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AttachmentParams {
+    /// Also process the attached PDFs
+    extract: Option<bool>,
+}
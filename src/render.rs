@@ -0,0 +1,1533 @@
+// turns pages into their svg text layer and their encoded images, the rendering options come from
+// the query string
+use crate::error::ApiError;
+use crate::links::{extract_page_links, LinkInfo};
+use crate::render_cache::DocumentCache;
+use crate::svg::{get_string_from_rects, page_svg};
+use crate::text::{
+    extract_page_text_groups, Granularity, PageTransform, RectResponse, TextGrouping,
+};
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgb, RgbImage, Rgba};
+use jpeg_encoder::{ColorType, Encoder as JpegEncoder};
+use pdfium_render::prelude::*;
+use rayon::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::io::Cursor;
+use std::sync::{mpsc as std_mpsc, Arc, OnceLock};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+// scales rendered for every page when the client doesn't ask for specific ones
+const DEFAULT_SCALES: [f32; 5] = [0.25, 0.5, 1.0, 1.5, 2.0];
+const MAX_SCALE: f32 = 4.0;
+// the text layer is meant to be selectable but invisible on top of the rendered images
+const DEFAULT_TEXT_FILL: &str = "transparent";
+// quality used for lossy image formats when the client doesn't ask for a specific one
+const DEFAULT_QUALITY: u8 = 85;
+// resolutions accepted by the dpi parameter
+const MIN_DPI: f32 = 10.0;
+const MAX_DPI: f32 = 1200.0;
+// every scale is a full render of each page, so don't let a single request ask for too many
+const MAX_SCALES_PER_REQUEST: usize = 8;
+// largest bitmap, in pixels, a single scale of a page can be rendered to when MAX_RENDER_MEGAPIXELS
+// is not set, a 50 megapixel rgba bitmap already takes 200MB
+const DEFAULT_MAX_RENDER_PIXELS: u64 = 50_000_000;
+static MAX_RENDER_PIXELS: OnceLock<u64> = OnceLock::new();
+// encoder speed used for avif when the client doesn't ask for a specific one, 1 is the slowest and
+// smallest and 10 the fastest
+#[cfg(feature = "avif")]
+const DEFAULT_AVIF_SPEED: u8 = 6;
+// css color names accepted as a page background, as `#rrggbbaa`
+const BACKGROUND_COLOR_NAMES: [(&str, &str); 12] = [
+    ("transparent", "#ffffff00"),
+    ("white", "#ffffff"),
+    ("black", "#000000"),
+    ("silver", "#c0c0c0"),
+    ("gray", "#808080"),
+    ("grey", "#808080"),
+    ("lightgray", "#d3d3d3"),
+    ("lightgrey", "#d3d3d3"),
+    ("darkgray", "#a9a9a9"),
+    ("darkgrey", "#a9a9a9"),
+    ("whitesmoke", "#f5f5f5"),
+    ("ivory", "#fffff0"),
+];
+// widest gap between two chars, in points, the gap_threshold parameter accepts
+const MAX_GAP_THRESHOLD: f32 = 50.0;
+// largest baseline shift, as a fraction of the font size, the y_band_tolerance parameter accepts
+const MAX_Y_BAND_TOLERANCE: f32 = 2.0;
+
+pub struct PageImage {
+    pub scale: f32,
+    pub format: &'static str,
+    pub mime_type: &'static str,
+    // shared with the render cache
+    pub data: Arc<Vec<u8>>,
+}
+
+pub struct PagePayload {
+    pub page_index: usize,
+    // missing when the client only asked for the images, or only for the svg
+    pub svg_text: Option<String>,
+    pub images: Option<Vec<PageImage>>,
+    // the plain text of the page, only with `output=text`
+    pub text: Option<String>,
+    // missing with `output=text`
+    pub links: Option<Vec<LinkInfo>>,
+    // the part of the page the images cover, the whole page when missing
+    pub clip: Option<RectResponse>,
+    pub embedded_images: Vec<EmbeddedImage>,
+    // time spent extracting the text & writing the svg, and rendering & encoding the images
+    pub text_time: Duration,
+    pub render_time: Duration,
+}
+
+// an image object of the page as stored in the PDF, before any scaling or clipping, encoded as png
+pub struct EmbeddedImage {
+    // position of the object among the objects of the page
+    pub object_index: usize,
+    pub bounds: RectResponse,
+    pub data: Vec<u8>,
+}
+
+// encodings the page images can be returned in
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageFormat {
+    Png,
+    // progressive jpegs show a blurry version of the whole image after the first bytes, baseline
+    // ones are decoded top to bottom
+    Jpeg {
+        progressive: bool,
+    },
+    WebP,
+    #[cfg(feature = "avif")]
+    Avif {
+        speed: u8,
+    },
+}
+
+impl ImageFormat {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "png" => Ok(ImageFormat::Png),
+            "jpeg" | "jpg" => Ok(ImageFormat::Jpeg { progressive: false }),
+            "webp" => Ok(ImageFormat::WebP),
+            #[cfg(feature = "avif")]
+            "avif" => Ok(ImageFormat::Avif {
+                speed: DEFAULT_AVIF_SPEED,
+            }),
+            #[cfg(not(feature = "avif"))]
+            "avif" => Err(
+                "avif is not supported by this build, the server has to be built with `--features avif`"
+                    .to_string(),
+            ),
+            _ => Err(format!(
+                "unsupported format `{}`, expected one of png, jpeg, webp or avif",
+                value
+            )),
+        }
+    }
+
+    // short name of the encoding, also used as the file extension
+    fn name(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg { .. } => "jpeg",
+            ImageFormat::WebP => "webp",
+            #[cfg(feature = "avif")]
+            ImageFormat::Avif { .. } => "avif",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg { .. } => "image/jpeg",
+            ImageFormat::WebP => "image/webp",
+            #[cfg(feature = "avif")]
+            ImageFormat::Avif { .. } => "image/avif",
+        }
+    }
+}
+
+// how a bitmap gets encoded. jpeg has no alpha channel, its transparent parts are blended onto the
+// background the page was rendered on
+#[derive(Clone, Copy)]
+pub struct ImageEncoding {
+    pub format: ImageFormat,
+    // only used by lossy formats
+    pub quality: u8,
+    pub background: Rgb<u8>,
+}
+
+impl ImageEncoding {
+    // the embedded images and thumbnails, always png
+    pub fn png() -> Self {
+        ImageEncoding {
+            format: ImageFormat::Png,
+            quality: DEFAULT_QUALITY,
+            background: Rgb([255, 255, 255]),
+        }
+    }
+}
+
+// what gets generated for every page, both the svg text layer and the images unless the client
+// asks for only one of them, or for the plain text alone
+#[derive(Clone, Copy, PartialEq, Eq, ToSchema)]
+#[schema(rename_all = "lowercase")]
+pub enum OutputMode {
+    Svg,
+    Images,
+    Both,
+    Text,
+    // both, sent as the files of a zip archive instead of json
+    Zip,
+}
+
+impl OutputMode {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "svg" => Ok(OutputMode::Svg),
+            "images" => Ok(OutputMode::Images),
+            "both" => Ok(OutputMode::Both),
+            "text" => Ok(OutputMode::Text),
+            "zip" => Ok(OutputMode::Zip),
+            _ => Err(format!(
+                "unsupported output `{}`, expected one of svg, images, both, text or zip",
+                value
+            )),
+        }
+    }
+
+    fn has_images(self) -> bool {
+        matches!(
+            self,
+            OutputMode::Images | OutputMode::Both | OutputMode::Zip
+        )
+    }
+}
+
+// how the page images and the svg text layer get rendered, shared by every endpoint that produces them
+pub struct RenderOptions {
+    // fill of the svg text, transparent by default so the layer can sit invisibly above the images
+    pub text_fill: String,
+    pub scales: Vec<f32>,
+    // cleared behind the page before rendering, answer books are rendered on a transparent background
+    pub background: PdfColor,
+    // renders the images in shades of gray, roughly a quarter of the raw pixel data
+    pub grayscale: bool,
+    pub format: ImageFormat,
+    // only used by lossy formats, 1 is the worst and 100 the best
+    pub quality: u8,
+    // renders only this region of the displayed page instead of all of it
+    pub clip: Option<RectResponse>,
+    // also returns the images stored in the pages
+    pub include_embedded_images: bool,
+    pub output: OutputMode,
+    // how the chars are grouped into the svg text, from `granularity`, `gap_threshold` and
+    // `y_band_tolerance`
+    pub text_grouping: TextGrouping,
+    // renders the page once at the largest scale and resizes that bitmap for the smaller scales,
+    // faster than a pdfium render per scale but thin lines and small text come out softer
+    pub downscale: bool,
+}
+
+impl RenderOptions {
+    fn is_transparent(&self) -> bool {
+        self.background.alpha() < u8::MAX
+    }
+
+    fn encoding(&self) -> ImageEncoding {
+        ImageEncoding {
+            format: self.format,
+            quality: self.quality,
+            background: Rgb([
+                self.background.red(),
+                self.background.green(),
+                self.background.blue(),
+            ]),
+        }
+    }
+
+    // the scale pdfium renders at when downscaling, the other scales are resized from it
+    pub fn downscale_scale(&self) -> Option<f32> {
+        if !self.downscale {
+            return None;
+        }
+        self.scales.iter().copied().reduce(f32::max)
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ApiError> {
+        // Extract the boolean which represents if we are dealing with a main book or with an answer book
+        let is_answer_book: bool = match params
+            .get("answer_book")
+            .and_then(|p| p.parse::<usize>().ok())
+        {
+            Some(value) => value != 0,
+            None => false,
+        };
+
+        // Extract the color behind the page, e.g. `?bg=lightgray` or `?background_color=%23f4ecd8`, it
+        // wins over the transparent background of answer books. `bg` is the shorter spelling and wins
+        // when both are given
+        let background = match params.get("bg").or_else(|| params.get("background_color")) {
+            Some(value) => parse_background_color(value).map_err(ApiError::InvalidParameter)?,
+            None if is_answer_book => PdfColor::WHITE.with_alpha(0),
+            None => PdfColor::WHITE,
+        };
+
+        // Extract the scales the images should be rendered at, e.g. `?scales=0.5,1.0` or `?dpi=150`.
+        // PDF points are 1/72 inch so a dpi is just another way to spell a scale, it wins over `scales`
+        let scales = match (params.get("dpi"), params.get("scales")) {
+            (Some(value), _) => vec![parse_dpi(value).map_err(ApiError::InvalidParameter)? / 72.0],
+            (None, Some(value)) => parse_scales(value).map_err(ApiError::InvalidParameter)?,
+            (None, None) => DEFAULT_SCALES.to_vec(),
+        };
+
+        // Extract whether the images should be rendered in grayscale, e.g. `?grayscale=1`
+        let grayscale = is_flag_set(params, "grayscale");
+
+        // Extract the encoding of the images, e.g. `?format=jpeg&quality=70` or `?format=avif&speed=4`
+        let format = match params.get("format") {
+            Some(value) => ImageFormat::parse(value).map_err(ApiError::InvalidParameter)?,
+            None => ImageFormat::Png,
+        };
+        #[cfg(feature = "avif")]
+        let format = match (format, params.get("speed")) {
+            (ImageFormat::Avif { .. }, Some(value)) => ImageFormat::Avif {
+                speed: parse_avif_speed(value).map_err(ApiError::InvalidParameter)?,
+            },
+            (format, _) => format,
+        };
+        // Extract whether the jpeg images are progressive, e.g. `?format=jpeg&progressive=1`
+        let format = match format {
+            ImageFormat::Jpeg { .. } => ImageFormat::Jpeg {
+                progressive: is_flag_set(params, "progressive"),
+            },
+            format => format,
+        };
+        let quality = match params.get("quality") {
+            Some(value) => value
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|quality| (1..=100).contains(quality))
+                .ok_or_else(|| {
+                    ApiError::InvalidParameter(format!(
+                        "quality must be a number between 1 and 100, got `{}`",
+                        value
+                    ))
+                })?,
+            None => DEFAULT_QUALITY,
+        };
+
+        // Extract the fill of the svg text, e.g. `?text_color=%23333333` or any css color with
+        // `?text_fill=rgb(0, 0, 0)`, the stricter text_color wins when both are given
+        let text_fill = match (params.get("text_color"), params.get("text_fill")) {
+            (Some(value), _) => parse_text_color(value).map_err(ApiError::InvalidParameter)?,
+            (None, Some(value)) => parse_text_fill(value).map_err(ApiError::InvalidParameter)?,
+            (None, None) => DEFAULT_TEXT_FILL.to_string(),
+        };
+
+        // Extract what should be generated for every page, e.g. `?output=svg`, both by default
+        let output = match params.get("output") {
+            Some(value) => OutputMode::parse(value).map_err(ApiError::InvalidParameter)?,
+            None => OutputMode::Both,
+        };
+
+        Ok(RenderOptions {
+            text_fill,
+            scales,
+            background,
+            grayscale,
+            format,
+            quality,
+            clip: parse_clip(params).map_err(ApiError::InvalidParameter)?,
+            include_embedded_images: is_flag_set(params, "include_embedded_images"),
+            output,
+            text_grouping: parse_text_grouping(params).map_err(ApiError::InvalidParameter)?,
+            downscale: is_flag_set(params, "downscale"),
+        })
+    }
+}
+
+// sets the largest bitmap a page can be rendered to, only the first call counts
+pub fn set_max_render_pixels(max_render_pixels: u64) {
+    let _ = MAX_RENDER_PIXELS.set(max_render_pixels);
+}
+
+fn max_render_pixels() -> u64 {
+    *MAX_RENDER_PIXELS.get_or_init(|| DEFAULT_MAX_RENDER_PIXELS)
+}
+
+// generates the svg text layer & images for each of the requested pages, handing every page
+// to `on_page` in order as soon as it's done.
+// Loading the same bytes into several documents doesn't buy anything since pdfium-render serializes
+// every pdfium call behind a process wide lock, instead the pages are pipelined: while the rayon pool
+// encodes the bitmaps of the previous pages this thread already rasterizes the next one. At most one
+// page per rayon thread (see PDFIUM_WORKERS) is in flight so memory stays bounded
+pub fn process_document(
+    document: &PdfDocument<'_>,
+    page_indices: Vec<usize>,
+    render_options: &RenderOptions,
+    cache: Option<DocumentCache>,
+    mut on_page: impl FnMut(PagePayload) -> Result<(), ApiError>,
+) -> Result<(), ApiError> {
+    let pages = document.pages();
+    let max_in_flight = rayon::current_num_threads().max(1);
+    let mut in_flight: VecDeque<std_mpsc::Receiver<Result<PagePayload, ApiError>>> =
+        VecDeque::new();
+
+    // Iterate over the requested pages to parse the text & generate the images
+    for page_index in page_indices {
+        let page = pages.get(page_index as PdfPageIndex)?;
+        let rendered_page = render_page(&page, page_index, render_options, cache.clone())?;
+
+        let (sender, receiver) = std_mpsc::channel();
+        let encoding = render_options.encoding();
+        rayon::spawn(move || {
+            let _ = sender.send(rendered_page.encode(encoding));
+        });
+        in_flight.push_back(receiver);
+
+        if in_flight.len() >= max_in_flight {
+            if let Some(receiver) = in_flight.pop_front() {
+                on_page(receive_encoded_page(receiver)?)?;
+            }
+        }
+    }
+    while let Some(receiver) = in_flight.pop_front() {
+        on_page(receive_encoded_page(receiver)?)?;
+    }
+    Ok(())
+}
+
+fn receive_encoded_page(
+    receiver: std_mpsc::Receiver<Result<PagePayload, ApiError>>,
+) -> Result<PagePayload, ApiError> {
+    receiver
+        .recv()
+        .map_err(|_| ApiError::Internal("the image encoder went away".to_string()))?
+}
+
+// parses the text into the svg layer & generates the images of a single page
+pub fn process_page(
+    page: &PdfPage<'_>,
+    page_index: usize,
+    render_options: &RenderOptions,
+    cache: Option<DocumentCache>,
+) -> Result<PagePayload, ApiError> {
+    render_page(page, page_index, render_options, cache)?.encode(render_options.encoding())
+}
+
+// a page whose text has been extracted and whose bitmaps have been rendered but not encoded yet,
+// everything that needs pdfium is done at this point
+struct RenderedPage {
+    page_index: usize,
+    svg_text: Option<String>,
+    text: Option<String>,
+    links: Option<Vec<LinkInfo>>,
+    clip: Option<RectResponse>,
+    // None when only the svg was asked for
+    bitmaps: Option<Vec<(f32, PageBitmap)>>,
+    // object index, bounds and pixels of the embedded images
+    embedded_images: Vec<(usize, RectResponse, DynamicImage)>,
+    text_time: Duration,
+    render_time: Duration,
+    // where the freshly encoded images get stored for the next requests
+    cache: Option<DocumentCache>,
+}
+
+enum PageBitmap {
+    Rendered(DynamicImage),
+    // encoded by a previous request for the same document and options
+    Cached(Arc<Vec<u8>>),
+    // another scale of the page rounds to the same bitmap size, the index of its entry
+    SameAs(usize),
+}
+
+impl RenderedPage {
+    fn encode(self, encoding: ImageEncoding) -> Result<PagePayload, ApiError> {
+        let started = Instant::now();
+        let images = self
+            .bitmaps
+            .map(|bitmaps| {
+                encode_page_images(self.page_index, bitmaps, encoding, self.cache.as_ref())
+            })
+            .transpose()?;
+        let embedded_images = self
+            .embedded_images
+            .into_iter()
+            .map(|(object_index, bounds, image)| {
+                Ok(EmbeddedImage {
+                    object_index,
+                    bounds,
+                    data: encode_image(image, ImageEncoding::png())?,
+                })
+            })
+            .collect::<Result<Vec<EmbeddedImage>, ApiError>>()?;
+        Ok(PagePayload {
+            page_index: self.page_index,
+            svg_text: self.svg_text,
+            images,
+            text: self.text,
+            links: self.links,
+            clip: self.clip,
+            embedded_images,
+            text_time: self.text_time,
+            render_time: self.render_time + started.elapsed(),
+        })
+    }
+}
+
+fn render_page(
+    page: &PdfPage<'_>,
+    page_index: usize,
+    render_options: &RenderOptions,
+    cache: Option<DocumentCache>,
+) -> Result<RenderedPage, ApiError> {
+    // Get page size info
+    let page_width = page.width().value;
+    let page_height = page.height().value;
+
+    // Parse the page for the text & generate svg string. When the svg is all the client asked for, or
+    // becomes a file of a zip, a page without text still gets an (empty) svg document rather than an
+    // empty string
+    let started = Instant::now();
+    let transform = PageTransform::new(page)?;
+    let svg_text = match render_options.output {
+        OutputMode::Images | OutputMode::Text => None,
+        output @ (OutputMode::Svg | OutputMode::Both | OutputMode::Zip) => {
+            let text_group_rects =
+                extract_page_text_groups(page, &transform, &render_options.text_grouping)?;
+            let write_svg = if output == OutputMode::Both {
+                get_string_from_rects
+            } else {
+                page_svg
+            };
+            Some(write_svg(
+                page_width,
+                page_height,
+                &text_group_rects,
+                &render_options.text_fill,
+            ))
+        }
+    };
+    // pdfium reports the line breaks of the page as `\r\n`
+    let (text, links) = if render_options.output == OutputMode::Text {
+        (Some(page.text()?.all()), None)
+    } else {
+        (None, Some(extract_page_links(page, &transform)))
+    };
+    let text_time = started.elapsed();
+    tracing::debug!(
+        page_index,
+        svg_ms = text_time.as_millis(),
+        "extracted the text of the page"
+    );
+
+    // Generate the images
+    let started = Instant::now();
+    let bitmaps = if render_options.output.has_images() {
+        Some(generate_page_images(
+            page,
+            page_index,
+            page_width,
+            page_height,
+            render_options,
+            cache.as_ref(),
+        )?)
+    } else {
+        None
+    };
+    let embedded_images = if render_options.include_embedded_images {
+        extract_embedded_images(page, &transform)
+    } else {
+        Vec::new()
+    };
+
+    Ok(RenderedPage {
+        page_index,
+        svg_text,
+        text,
+        links,
+        clip: render_options.clip,
+        bitmaps,
+        embedded_images,
+        text_time,
+        render_time: started.elapsed(),
+        cache,
+    })
+}
+
+// boolean query parameters are set with `1` or `true`
+pub fn is_flag_set(params: &HashMap<String, String>, name: &str) -> bool {
+    params.get(name).is_some_and(|value| {
+        value.eq_ignore_ascii_case("true") || value.parse::<usize>().is_ok_and(|value| value != 0)
+    })
+}
+
+// the fill ends up inside the style attribute of every text element, so only allow the characters
+// css colors are made of (`transparent`, `red`, `#333333`, `rgb(0, 0, 0)`, ...)
+fn parse_text_fill(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let is_css_color = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "#(),.% ".contains(c));
+    if !is_css_color {
+        return Err(format!(
+            "invalid text_fill `{}`, expected a css color",
+            value
+        ));
+    }
+    Ok(value.to_string())
+}
+
+fn parse_dpi(value: &str) -> Result<f32, String> {
+    let dpi = value
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| format!("invalid dpi `{}`", value.trim()))?;
+    if !(MIN_DPI..=MAX_DPI).contains(&dpi) {
+        return Err(format!(
+            "dpi {} is out of range, it must be between {} and {}",
+            dpi, MIN_DPI, MAX_DPI
+        ));
+    }
+    Ok(dpi)
+}
+
+#[cfg(feature = "avif")]
+fn parse_avif_speed(value: &str) -> Result<u8, String> {
+    value
+        .trim()
+        .parse::<u8>()
+        .ok()
+        .filter(|speed| (1..=10).contains(speed))
+        .ok_or_else(|| format!("speed must be a number between 1 and 10, got `{}`", value))
+}
+
+// `granularity`, `gap_threshold` in points and `y_band_tolerance` as a fraction of the font size, all
+// optional
+fn parse_text_grouping(params: &HashMap<String, String>) -> Result<TextGrouping, String> {
+    let mut grouping = TextGrouping::default();
+    if let Some(value) = params.get("granularity") {
+        grouping.granularity = Granularity::parse(value)?;
+    }
+    if let Some(value) = params.get("gap_threshold") {
+        let gap_threshold = value
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|gap_threshold| (0.0..=MAX_GAP_THRESHOLD).contains(gap_threshold))
+            .ok_or_else(|| {
+                format!(
+                    "gap_threshold must be a number of points between 0 and {}, got `{}`",
+                    MAX_GAP_THRESHOLD, value
+                )
+            })?;
+        grouping.gap_threshold = Some(gap_threshold);
+    }
+    if let Some(value) = params.get("y_band_tolerance") {
+        grouping.y_band_tolerance = value
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|tolerance| *tolerance > 0.0 && *tolerance <= MAX_Y_BAND_TOLERANCE)
+            .ok_or_else(|| {
+                format!(
+                    "y_band_tolerance must be a fraction of the font size greater than 0 and at most {}, got `{}`",
+                    MAX_Y_BAND_TOLERANCE, value
+                )
+            })?;
+    }
+    Ok(grouping)
+}
+
+// `#rgb`, `#rgba`, `#rrggbb`, `#rrggbbaa` or one of the BACKGROUND_COLOR_NAMES, the alpha defaults
+// to opaque
+fn parse_background_color(value: &str) -> Result<PdfColor, String> {
+    let value = value.trim();
+    let invalid = || {
+        format!(
+            "invalid background color `{}`, expected a hex color like `#rrggbb` or `#rrggbbaa`, or a css color name like `lightgray`",
+            value
+        )
+    };
+    let hex = BACKGROUND_COLOR_NAMES
+        .iter()
+        .find(|(name, _)| value.eq_ignore_ascii_case(name))
+        .map_or(value, |(_, hex)| *hex);
+    let digits = hex
+        .strip_prefix('#')
+        .filter(|digits| {
+            matches!(digits.len(), 3 | 4 | 6 | 8) && digits.chars().all(|c| c.is_ascii_hexdigit())
+        })
+        .ok_or_else(invalid)?;
+    // the short forms repeat every digit, `#abc` is `#aabbcc`
+    let digits = if digits.len() <= 4 {
+        digits.chars().flat_map(|c| [c, c]).collect()
+    } else {
+        digits.to_string()
+    };
+    let channel = |index: usize| {
+        digits
+            .get(index * 2..index * 2 + 2)
+            .map_or(Ok(u8::MAX), |hex| u8::from_str_radix(hex, 16))
+            .map_err(|_| invalid())
+    };
+    Ok(PdfColor::new(
+        channel(0)?,
+        channel(1)?,
+        channel(2)?,
+        channel(3)?,
+    ))
+}
+
+// `clip_x`, `clip_y`, `clip_w` and `clip_h` in points from the top left corner of the displayed page,
+// all four or none. Whether the rectangle fits is checked against every page when it is rendered
+fn parse_clip(params: &HashMap<String, String>) -> Result<Option<RectResponse>, String> {
+    let names = ["clip_x", "clip_y", "clip_w", "clip_h"];
+    let values = names.map(|name| params.get(name));
+    if values.iter().all(Option::is_none) {
+        return Ok(None);
+    }
+
+    let mut parsed = [0.0f32; 4];
+    for ((name, value), parsed) in names.iter().zip(values).zip(parsed.iter_mut()) {
+        let value = value.ok_or_else(|| {
+            format!(
+                "{} is missing, clip_x, clip_y, clip_w and clip_h go together",
+                name
+            )
+        })?;
+        // the corner can sit on the edge of the page, the size can't be empty
+        let is_size = *name == "clip_w" || *name == "clip_h";
+        *parsed = value
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|value| value.is_finite() && (*value > 0.0 || (!is_size && *value == 0.0)))
+            .ok_or_else(|| {
+                format!(
+                    "{} must be a {} number of points, got `{}`",
+                    name,
+                    if is_size { "positive" } else { "non-negative" },
+                    value
+                )
+            })?;
+    }
+
+    let [x, y, width, height] = parsed;
+    Ok(Some(RectResponse {
+        x,
+        y,
+        width,
+        height,
+    }))
+}
+
+// `transparent` or a hex color, `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa`
+fn parse_text_color(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("transparent") {
+        return Ok("transparent".to_string());
+    }
+    let is_hex_color = value.strip_prefix('#').is_some_and(|digits| {
+        matches!(digits.len(), 3 | 4 | 6 | 8) && digits.chars().all(|c| c.is_ascii_hexdigit())
+    });
+    if !is_hex_color {
+        return Err(format!(
+            "invalid text_color `{}`, expected `transparent` or a hex color like `#333333`",
+            value
+        ));
+    }
+    Ok(value.to_string())
+}
+
+// parses a comma separated list of scales, every scale has to be in the (0, MAX_SCALE] range
+fn parse_scales(value: &str) -> Result<Vec<f32>, String> {
+    let scales = value
+        .split(',')
+        .map(|raw| {
+            let scale = raw
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| format!("invalid scale `{}`", raw.trim()))?;
+            // written as a negated range check so NaN is rejected too
+            if !(scale > 0.0 && scale <= MAX_SCALE) {
+                return Err(format!(
+                    "scale {} is out of range, it must be greater than 0 and at most {}",
+                    scale, MAX_SCALE
+                ));
+            }
+            Ok(scale)
+        })
+        .collect::<Result<Vec<f32>, String>>()?;
+
+    if scales.len() > MAX_SCALES_PER_REQUEST {
+        return Err(format!(
+            "too many scales requested, at most {} are allowed",
+            MAX_SCALES_PER_REQUEST
+        ));
+    }
+    Ok(scales)
+}
+
+// function to return the raw page bitmaps at specific scales
+// pdfium can only rasterize one bitmap at a time (pdfium-render holds a process wide lock for as long
+// as a Pdfium instance lives, so a second instance per thread would just block), the renders happen
+// sequentially and only the encoding, which is most of the cost, is spread across rayon's pool.
+// Scales already in the render cache aren't rendered again
+fn generate_page_images(
+    page: &PdfPage<'_>,
+    page_index: usize,
+    page_width: f32,
+    page_height: f32,
+    render_options: &RenderOptions,
+    cache: Option<&DocumentCache>,
+) -> Result<Vec<(f32, PageBitmap)>, ApiError> {
+    let _span = tracing::info_span!("generate_page_images", page_index).entered();
+    if let Some(clip) = render_options.clip {
+        if clip.x + clip.width > page_width || clip.y + clip.height > page_height {
+            return Err(ApiError::InvalidParameter(format!(
+                "the clip rectangle {}x{} at ({}, {}) doesn't fit in page {}, it is {}x{} points",
+                clip.width, clip.height, clip.x, clip.y, page_index, page_width, page_height
+            )));
+        }
+    }
+    let bitmap_size = |scale: f32| match render_options.clip {
+        Some(clip) => (
+            ((clip.width * scale).round() as i32).max(1),
+            ((clip.height * scale).round() as i32).max(1),
+        ),
+        None => ((page_width * scale) as i32, (page_height * scale) as i32),
+    };
+    // checked for every scale before pdfium allocates anything, a huge page at a high scale would
+    // otherwise take gigabytes of memory
+    for scale in render_options.scales.iter() {
+        let (width, height) = bitmap_size(*scale);
+        check_bitmap_size(page_index, *scale, width, height, max_render_pixels())?;
+    }
+
+    render_scales(
+        &render_options.scales,
+        render_options.downscale_scale(),
+        bitmap_size,
+        |scale| cache.and_then(|cache| cache.get(page_index, scale)),
+        |scale, width, height| render_page_bitmap(page, scale, width, height, render_options),
+    )
+}
+
+// the bitmap of every scale, in the order of `scales`. A scale whose bitmap size was already seen
+// points back to that entry, a cached one isn't rendered again, and with `downscale_scale` the
+// other scales are resized from a single render at that scale instead of rendered on their own
+fn render_scales(
+    scales: &[f32],
+    downscale_scale: Option<f32>,
+    bitmap_size: impl Fn(f32) -> (i32, i32),
+    cached: impl Fn(f32) -> Option<Arc<Vec<u8>>>,
+    mut render: impl FnMut(f32, i32, i32) -> Result<DynamicImage, ApiError>,
+) -> Result<Vec<(f32, PageBitmap)>, ApiError> {
+    let mut rendered: Vec<(f32, PageBitmap)> = Vec::new();
+    // bitmap size of every entry of `rendered`
+    let mut sizes: Vec<(i32, i32)> = Vec::new();
+    // with `downscale=1`, the render at the largest scale every other scale is resized from
+    let mut largest: Option<DynamicImage> = None;
+    for scale in scales.iter() {
+        let (width, height) = bitmap_size(*scale);
+        // e.g. `scales=1.0,1.0`, or two scales close enough to round to the same size
+        if let Some(index) = sizes.iter().position(|size| *size == (width, height)) {
+            rendered.push((*scale, PageBitmap::SameAs(index)));
+            sizes.push((width, height));
+            continue;
+        }
+        sizes.push((width, height));
+        if let Some(data) = cached(*scale) {
+            rendered.push((*scale, PageBitmap::Cached(data)));
+            continue;
+        }
+
+        let dynamic_image = match downscale_scale {
+            Some(largest_scale) => {
+                let largest = match largest {
+                    Some(ref largest) => largest,
+                    None => {
+                        let (largest_width, largest_height) = bitmap_size(largest_scale);
+                        largest.insert(render(largest_scale, largest_width, largest_height)?)
+                    }
+                };
+                if (largest.width() as i32, largest.height() as i32) == (width, height) {
+                    largest.clone()
+                } else {
+                    largest.resize_exact(
+                        width.max(1) as u32,
+                        height.max(1) as u32,
+                        FilterType::Lanczos3,
+                    )
+                }
+            }
+            None => render(*scale, width, height)?,
+        };
+        rendered.push((*scale, PageBitmap::Rendered(dynamic_image)));
+    }
+    Ok(rendered)
+}
+
+// fails when a `width` by `height` bitmap of the page would have more than `max_pixels` pixels
+fn check_bitmap_size(
+    page_index: usize,
+    scale: f32,
+    width: i32,
+    height: i32,
+    max_pixels: u64,
+) -> Result<(), ApiError> {
+    let pixels = width.max(0) as u64 * height.max(0) as u64;
+    if pixels > max_pixels {
+        return Err(ApiError::InvalidParameter(format!(
+            "page {} at scale {} would be a {}x{} bitmap, more than the {} megapixels allowed, ask for a smaller scale or clip",
+            page_index,
+            scale,
+            width,
+            height,
+            max_pixels as f64 / 1_000_000.0
+        )));
+    }
+    Ok(())
+}
+
+// a single pdfium render of the page (or of its clip) to a `width` by `height` bitmap
+fn render_page_bitmap(
+    page: &PdfPage<'_>,
+    scale: f32,
+    width: i32,
+    height: i32,
+    render_options: &RenderOptions,
+) -> Result<DynamicImage, ApiError> {
+    let render_config = PdfRenderConfig::new()
+        .set_format(PdfBitmapFormat::BGRA)
+        .set_reverse_byte_order(true)
+        .set_clear_color(render_options.background);
+    let render_config = match render_options.clip {
+        // a bitmap the size of the clip, the page is moved so the clip lands on its top left
+        // corner before being scaled, in the top left based coordinates of the displayed page
+        Some(clip) => render_config
+            .set_fixed_size(width, height)
+            .translate(PdfPoints::new(-clip.x), PdfPoints::new(-clip.y))?
+            .scale(scale, scale)?,
+        None => render_config.set_target_size(width, height),
+    };
+
+    let dynamic_image = page.render_with_config(&render_config)?.as_image(); // Renders this page to an image::DynamicImage
+
+    Ok(to_output_color(
+        dynamic_image,
+        render_options.grayscale,
+        render_options.is_transparent(),
+    ))
+}
+
+// a single 8-bit channel (plus alpha for answer books) instead of four when rendering in grayscale
+fn to_output_color(image: DynamicImage, grayscale: bool, transparent: bool) -> DynamicImage {
+    match (grayscale, transparent) {
+        (true, true) => DynamicImage::ImageLumaA8(image.into_luma_alpha8()),
+        (true, false) => DynamicImage::ImageLuma8(image.into_luma8()),
+        (false, _) => DynamicImage::ImageRgba8(image.into_rgba8()),
+    }
+}
+
+// decodes the image objects found directly on the page, images nested in form objects are not
+// looked at and the ones pdfium can't decode are skipped
+fn extract_embedded_images(
+    page: &PdfPage<'_>,
+    transform: &PageTransform,
+) -> Vec<(usize, RectResponse, DynamicImage)> {
+    page.objects()
+        .iter()
+        .enumerate()
+        .filter_map(|(object_index, object)| {
+            let image = object.as_image_object()?.get_raw_image().ok()?;
+            let (x, y, width, height) = transform.rect_to_display(&object.bounds().ok()?.to_rect());
+            Some((
+                object_index,
+                RectResponse {
+                    x,
+                    y,
+                    width,
+                    height,
+                },
+                image,
+            ))
+        })
+        .collect()
+}
+
+// function to return the images as buffers in the requested format, every new encoding is cached
+fn encode_page_images(
+    page_index: usize,
+    rendered: Vec<(f32, PageBitmap)>,
+    encoding: ImageEncoding,
+    cache: Option<&DocumentCache>,
+) -> Result<Vec<PageImage>, ApiError> {
+    // par_iter keeps the order of the scales when collecting
+    let encoded = rendered
+        .into_par_iter()
+        .map(|(scale, bitmap)| {
+            let bitmap = match bitmap {
+                PageBitmap::Rendered(dynamic_image) => {
+                    let data = Arc::new(encode_image(dynamic_image, encoding)?);
+                    if let Some(cache) = cache {
+                        cache.put(page_index, scale, data.clone());
+                    }
+                    PageBitmap::Cached(data)
+                }
+                bitmap => bitmap,
+            };
+            Ok((scale, bitmap))
+        })
+        .collect::<Result<Vec<(f32, PageBitmap)>, ApiError>>()?;
+
+    // the duplicated scales share the data of the entry they point back to
+    let mut images: Vec<PageImage> = Vec::with_capacity(encoded.len());
+    for (scale, bitmap) in encoded {
+        let data = match bitmap {
+            PageBitmap::Cached(data) => data,
+            PageBitmap::SameAs(index) => {
+                let data = images[index].data.clone();
+                if let Some(cache) = cache {
+                    cache.put(page_index, scale, data.clone());
+                }
+                data
+            }
+            PageBitmap::Rendered(_) => {
+                return Err(ApiError::Internal(
+                    "a page image was left unencoded".to_string(),
+                ))
+            }
+        };
+        images.push(PageImage {
+            scale,
+            format: encoding.format.name(),
+            mime_type: encoding.format.mime_type(),
+            data,
+        });
+    }
+    Ok(images)
+}
+
+pub fn encode_image(image: DynamicImage, encoding: ImageEncoding) -> Result<Vec<u8>, ApiError> {
+    let ImageEncoding {
+        format,
+        quality,
+        background,
+    } = encoding;
+    let mut image_buffer = Vec::new();
+    let mut writer = Cursor::new(&mut image_buffer);
+    let result = match format {
+        ImageFormat::Png => image.write_to(&mut writer, image::ImageFormat::Png),
+        ImageFormat::Jpeg { progressive } => {
+            return encode_jpeg(image, quality, progressive, background)
+        }
+        // the image crate only ships a lossless webp encoder, libwebp handles the lossy qualities
+        ImageFormat::WebP if quality == 100 => {
+            image.write_with_encoder(WebPEncoder::new_lossless(&mut writer))
+        }
+        ImageFormat::WebP => {
+            let rgba = image.into_rgba8();
+            let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode_simple(false, quality as f32)
+                .map_err(|err| ApiError::RenderFailed(format!("{:?}", err)))?;
+            image_buffer.extend_from_slice(&encoded);
+            Ok(())
+        }
+        #[cfg(feature = "avif")]
+        ImageFormat::Avif { speed } => image.write_with_encoder(
+            image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut writer, speed, quality),
+        ),
+    };
+    result.map_err(|err| ApiError::RenderFailed(err.to_string()))?;
+    Ok(image_buffer)
+}
+
+// jpeg-encoder rather than the encoder of the image crate, which can't write progressive jpegs. jpeg
+// has no alpha channel, the image is flattened onto the background first
+fn encode_jpeg(
+    image: DynamicImage,
+    quality: u8,
+    progressive: bool,
+    background: Rgb<u8>,
+) -> Result<Vec<u8>, ApiError> {
+    let image = flatten_onto(image, background);
+    let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => {
+            return Err(ApiError::RenderFailed(format!(
+                "a {}x{} image is too large for a jpeg, which is limited to 65535 pixels per side",
+                image.width(),
+                image.height()
+            )))
+        }
+    };
+    let (data, color_type) = match image {
+        DynamicImage::ImageLuma8(gray) => (gray.into_raw(), ColorType::Luma),
+        image => (image.into_rgb8().into_raw(), ColorType::Rgb),
+    };
+    let mut image_buffer = Vec::new();
+    let mut encoder = JpegEncoder::new(&mut image_buffer, quality);
+    encoder.set_progressive(progressive);
+    encoder
+        .encode(&data, width, height, color_type)
+        .map_err(|err| ApiError::RenderFailed(err.to_string()))?;
+    Ok(image_buffer)
+}
+
+// blends every pixel onto an opaque background, just dropping the alpha channel would turn the
+// transparent parts of answer books into whatever color pdfium left behind them. A translucent
+// background color is made opaque, white for answer books. Grayscale images stay gray
+fn flatten_onto(image: DynamicImage, background: Rgb<u8>) -> DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+    let is_grayscale = !image.color().has_color();
+    let rgba = image.into_rgba8();
+    let flattened =
+        DynamicImage::ImageRgb8(RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let Rgba([r, g, b, a]) = *rgba.get_pixel(x, y);
+            let Rgb([background_r, background_g, background_b]) = background;
+            let blend = |channel: u8, background: u8| {
+                ((channel as u16 * a as u16 + background as u16 * (255 - a as u16) + 127) / 255)
+                    as u8
+            };
+            Rgb([
+                blend(r, background_r),
+                blend(g, background_g),
+                blend(b, background_b),
+            ])
+        }));
+    if is_grayscale {
+        DynamicImage::ImageLuma8(flattened.into_luma8())
+    } else {
+        flattened
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what pdfium hands back, a bgra bitmap converted to rgba
+    fn rendered_page() -> DynamicImage {
+        DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            4,
+            2,
+            Rgba([200, 100, 50, 255]),
+        ))
+    }
+
+    #[test]
+    fn grayscale_output_has_a_single_channel() {
+        let image = to_output_color(rendered_page(), true, false);
+        assert_eq!(image.color().channel_count(), 1);
+
+        let png = encode_image(image, ImageEncoding::png()).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+    }
+
+    #[test]
+    fn transparent_grayscale_output_keeps_its_alpha_channel() {
+        let image = to_output_color(rendered_page(), true, true);
+        assert_eq!(image.color().channel_count(), 2);
+
+        let png = encode_image(image, ImageEncoding::png()).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::La8);
+    }
+
+    #[test]
+    fn color_output_has_four_channels() {
+        let image = to_output_color(rendered_page(), false, false);
+        assert_eq!(image.color().channel_count(), 4);
+    }
+
+    #[test]
+    fn grayscale_jpeg_is_encoded_as_gray() {
+        let image = to_output_color(rendered_page(), true, true);
+        let encoding = ImageEncoding {
+            format: ImageFormat::Jpeg { progressive: false },
+            ..ImageEncoding::png()
+        };
+        let jpeg = encode_image(image, encoding).unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+    }
+
+    #[test]
+    fn jpeg_composites_onto_the_background() {
+        // a fully transparent page rendered on a translucent sepia background
+        let transparent =
+            DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 0])));
+        let flattened = flatten_onto(transparent, Rgb([244, 236, 216])).into_rgb8();
+        assert_eq!(*flattened.get_pixel(0, 0), Rgb([244, 236, 216]));
+
+        let half_black =
+            DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 128])));
+        let flattened = flatten_onto(half_black, Rgb([200, 100, 0])).into_rgb8();
+        assert_eq!(*flattened.get_pixel(0, 0), Rgb([100, 50, 0]));
+    }
+
+    // a jpeg is progressive when its frame header is SOF2 instead of the SOF0 of baseline jpegs
+    fn is_progressive_jpeg(jpeg: &[u8]) -> bool {
+        jpeg.windows(2).any(|marker| marker == [0xFF, 0xC2])
+    }
+
+    #[test]
+    fn progressive_jpeg_decodes_to_the_rendered_page() {
+        let encoding = ImageEncoding {
+            format: ImageFormat::Jpeg { progressive: true },
+            ..ImageEncoding::png()
+        };
+        let jpeg = encode_image(rendered_page(), encoding).unwrap();
+        assert!(is_progressive_jpeg(&jpeg));
+
+        let decoded = image::load_from_memory(&jpeg).unwrap().into_rgb8();
+        assert_eq!(decoded.dimensions(), (4, 2));
+        // lossy, but a flat color survives
+        let Rgb([r, g, b]) = *decoded.get_pixel(1, 1);
+        assert!(r.abs_diff(200) <= 4 && g.abs_diff(100) <= 4 && b.abs_diff(50) <= 4);
+    }
+
+    #[test]
+    fn baseline_jpeg_stays_the_default() {
+        let page = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 2, Rgb([200, 100, 50])));
+        let encoding = ImageEncoding {
+            format: ImageFormat::Jpeg { progressive: false },
+            ..ImageEncoding::png()
+        };
+        let jpeg = encode_image(page, encoding).unwrap();
+        assert!(!is_progressive_jpeg(&jpeg));
+    }
+
+    #[test]
+    fn progressive_jpeg_composites_onto_the_background() {
+        let transparent =
+            DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(16, 16, Rgba([0, 0, 0, 0])));
+        let encoding = ImageEncoding {
+            format: ImageFormat::Jpeg { progressive: true },
+            quality: 100,
+            background: Rgb([244, 236, 216]),
+        };
+        let jpeg = encode_image(transparent, encoding).unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap().into_rgb8();
+        let Rgb([r, g, b]) = *decoded.get_pixel(8, 8);
+        assert!(r.abs_diff(244) <= 2 && g.abs_diff(236) <= 2 && b.abs_diff(216) <= 2);
+    }
+
+    #[test]
+    fn progressive_is_only_set_with_the_flag() {
+        let format = |params: &[(&str, &str)]| {
+            let params = params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>();
+            RenderOptions::from_params(&params).unwrap().format
+        };
+        assert!(format(&[("format", "jpeg")]) == ImageFormat::Jpeg { progressive: false });
+        assert!(
+            format(&[("format", "jpeg"), ("progressive", "1")])
+                == ImageFormat::Jpeg { progressive: true }
+        );
+        // meaningless for the other formats
+        assert!(format(&[("progressive", "1")]) == ImageFormat::Png);
+    }
+
+    #[test]
+    fn jpeg_encoding_uses_the_background_of_the_render_options() {
+        let params = HashMap::from([
+            ("format".to_string(), "jpeg".to_string()),
+            ("bg".to_string(), "#00336680".to_string()),
+        ]);
+        let encoding = RenderOptions::from_params(&params).unwrap().encoding();
+        assert_eq!(encoding.background, Rgb([0x00, 0x33, 0x66]));
+    }
+
+    // the bitmap size of every scale of a 100 by 100 points page
+    fn page_bitmap_size(scale: f32) -> (i32, i32) {
+        ((100.0 * scale) as i32, (100.0 * scale) as i32)
+    }
+
+    // stands in for pdfium, a blank bitmap of the requested size. Every scale it's asked for is
+    // pushed on `renders`
+    fn blank_render(
+        renders: &mut Vec<f32>,
+    ) -> impl FnMut(f32, i32, i32) -> Result<DynamicImage, ApiError> + '_ {
+        |scale, width, height| {
+            renders.push(scale);
+            Ok(DynamicImage::ImageRgba8(image::RgbaImage::new(
+                width as u32,
+                height as u32,
+            )))
+        }
+    }
+
+    #[test]
+    fn render_scales_renders_a_repeated_scale_once() {
+        let mut renders = Vec::new();
+        let rendered = render_scales(
+            &[1.0, 1.0],
+            None,
+            page_bitmap_size,
+            |_| None,
+            blank_render(&mut renders),
+        )
+        .unwrap();
+        assert_eq!(renders, [1.0]);
+        assert_eq!(rendered.len(), 2);
+        assert!(matches!(rendered[0].1, PageBitmap::Rendered(_)));
+        assert!(matches!(rendered[1].1, PageBitmap::SameAs(0)));
+    }
+
+    #[test]
+    fn render_scales_shares_the_render_of_scales_rounding_to_the_same_size() {
+        // 2.005 is a 200.5 pixels wide bitmap, truncated to the 200 pixels of scale 2, e.g. a dpi
+        // of 144.36 next to `scales=2`
+        let scales = [1.5, 2.0, 2.005];
+        let mut renders = Vec::new();
+        let rendered = render_scales(
+            &scales,
+            None,
+            page_bitmap_size,
+            |_| None,
+            blank_render(&mut renders),
+        )
+        .unwrap();
+        assert_eq!(renders, [1.5, 2.0]);
+        assert!(matches!(rendered[2].1, PageBitmap::SameAs(1)));
+
+        let images = encode_page_images(0, rendered, ImageEncoding::png(), None).unwrap();
+        // every scale keeps its entry and its label, in the requested order
+        assert_eq!(
+            images.iter().map(|image| image.scale).collect::<Vec<_>>(),
+            scales
+        );
+        assert!(Arc::ptr_eq(&images[2].data, &images[1].data));
+        let size = |data: &[u8]| {
+            let image = image::load_from_memory(data).unwrap();
+            (image.width(), image.height())
+        };
+        assert_eq!(size(&images[0].data), (150, 150));
+        assert_eq!(size(&images[2].data), (200, 200));
+    }
+
+    #[test]
+    fn downscale_resizes_to_the_sizes_of_a_direct_render() {
+        let params = HashMap::from([
+            ("scales".to_string(), "0.5,1.25,2".to_string()),
+            ("downscale".to_string(), "1".to_string()),
+        ]);
+        let render_options = RenderOptions::from_params(&params).unwrap();
+        assert_eq!(render_options.downscale_scale(), Some(2.0));
+        let sizes = |rendered: Vec<(f32, PageBitmap)>| {
+            rendered
+                .into_iter()
+                .map(|(scale, bitmap)| match bitmap {
+                    PageBitmap::Rendered(image) => (scale, image.width(), image.height()),
+                    _ => panic!("scale {} wasn't rendered", scale),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut renders = Vec::new();
+        let direct = render_scales(
+            &render_options.scales,
+            None,
+            page_bitmap_size,
+            |_| None,
+            blank_render(&mut renders),
+        )
+        .unwrap();
+        assert_eq!(renders, [0.5, 1.25, 2.0]);
+
+        let mut renders = Vec::new();
+        let downscaled = render_scales(
+            &render_options.scales,
+            render_options.downscale_scale(),
+            page_bitmap_size,
+            |_| None,
+            blank_render(&mut renders),
+        )
+        .unwrap();
+        // a single pdfium render, at the largest scale
+        assert_eq!(renders, [2.0]);
+        assert_eq!(sizes(downscaled), sizes(direct));
+    }
+
+    fn clip(values: [&str; 4]) -> Result<Option<RectResponse>, String> {
+        let params = ["clip_x", "clip_y", "clip_w", "clip_h"]
+            .into_iter()
+            .zip(values)
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        parse_clip(&params)
+    }
+
+    #[test]
+    fn parse_clip_accepts_a_corner_on_the_edge_of_the_page() {
+        let clip = clip(["0", "0", "100", "50.5"]).unwrap().unwrap();
+        assert_eq!(
+            (clip.x, clip.y, clip.width, clip.height),
+            (0.0, 0.0, 100.0, 50.5)
+        );
+        assert!(parse_clip(&HashMap::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_clip_rejects_negative_corners_and_empty_sizes() {
+        assert_eq!(
+            clip(["-1", "0", "100", "100"]).err().as_deref(),
+            Some("clip_x must be a non-negative number of points, got `-1`")
+        );
+        assert_eq!(
+            clip(["0", "-0.5", "100", "100"]).err().as_deref(),
+            Some("clip_y must be a non-negative number of points, got `-0.5`")
+        );
+        assert_eq!(
+            clip(["0", "0", "0", "100"]).err().as_deref(),
+            Some("clip_w must be a positive number of points, got `0`")
+        );
+        assert_eq!(
+            clip(["0", "0", "100", "-3"]).err().as_deref(),
+            Some("clip_h must be a positive number of points, got `-3`")
+        );
+        assert!(clip(["0", "0", "100", "NaN"]).is_err());
+    }
+
+    #[test]
+    fn check_bitmap_size_allows_bitmaps_up_to_the_limit() {
+        assert!(check_bitmap_size(0, 1.0, 1000, 1000, 1_000_000).is_ok());
+        assert!(check_bitmap_size(0, 1.0, 0, 0, 1_000_000).is_ok());
+    }
+
+    #[test]
+    fn check_bitmap_size_rejects_bitmaps_past_the_limit() {
+        match check_bitmap_size(2, 4.0, 1001, 1000, 1_000_000) {
+            Err(ApiError::InvalidParameter(message)) => assert_eq!(
+                message,
+                "page 2 at scale 4 would be a 1001x1000 bitmap, more than the 1 megapixels allowed, ask for a smaller scale or clip"
+            ),
+            _ => panic!("a bitmap past the limit must be rejected"),
+        }
+    }
+
+    #[test]
+    fn parse_scales_accepts_a_list_of_scales() {
+        assert_eq!(parse_scales("0.5, 1,2.0"), Ok(vec![0.5, 1.0, 2.0]));
+        assert_eq!(parse_scales("4"), Ok(vec![4.0]));
+    }
+
+    #[test]
+    fn parse_scales_rejects_out_of_range_and_invalid_scales() {
+        assert!(parse_scales("0").is_err());
+        assert!(parse_scales("-1").is_err());
+        assert!(parse_scales("4.5").is_err());
+        assert!(parse_scales("NaN").is_err());
+        assert!(parse_scales("1,big").is_err());
+        assert!(parse_scales("").is_err());
+    }
+
+    #[test]
+    fn parse_scales_limits_the_number_of_scales() {
+        assert!(parse_scales("1,1,1,1,1,1,1,1").is_ok());
+        assert!(parse_scales("1,1,1,1,1,1,1,1,1").is_err());
+    }
+
+    fn channels(color: PdfColor) -> [u8; 4] {
+        [color.red(), color.green(), color.blue(), color.alpha()]
+    }
+
+    #[test]
+    fn parse_background_color_accepts_every_hex_form() {
+        assert_eq!(
+            parse_background_color("#f4ecd8").map(channels),
+            Ok([0xf4, 0xec, 0xd8, 0xff])
+        );
+        assert_eq!(
+            parse_background_color("#F4ECD880").map(channels),
+            Ok([0xf4, 0xec, 0xd8, 0x80])
+        );
+        assert_eq!(
+            parse_background_color("#abc").map(channels),
+            Ok([0xaa, 0xbb, 0xcc, 0xff])
+        );
+        assert_eq!(
+            parse_background_color("#abc0").map(channels),
+            Ok([0xaa, 0xbb, 0xcc, 0x00])
+        );
+    }
+
+    #[test]
+    fn parse_background_color_accepts_color_names() {
+        assert_eq!(
+            parse_background_color(" LightGray ").map(channels),
+            Ok([0xd3, 0xd3, 0xd3, 0xff])
+        );
+        assert_eq!(
+            parse_background_color("transparent").map(channels),
+            Ok([0xff, 0xff, 0xff, 0x00])
+        );
+    }
+
+    #[test]
+    fn parse_background_color_rejects_invalid_colors() {
+        for value in [
+            "",
+            "f4ecd8",
+            "#f4ecd",
+            "#f4ecdg",
+            "#f4ecd8001",
+            "rebeccapurple",
+        ] {
+            assert!(parse_background_color(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_text_color_accepts_transparent_and_hex_colors() {
+        assert_eq!(
+            parse_text_color("Transparent"),
+            Ok("transparent".to_string())
+        );
+        assert_eq!(parse_text_color(" #333 "), Ok("#333".to_string()));
+        assert_eq!(parse_text_color("#33333380"), Ok("#33333380".to_string()));
+    }
+
+    #[test]
+    fn parse_text_color_rejects_anything_else() {
+        for value in ["red", "#33", "#3333333", "#zzz", "rgb(0, 0, 0)"] {
+            assert!(parse_text_color(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_text_fill_accepts_css_colors() {
+        for value in [
+            "transparent",
+            "red",
+            "#333333",
+            "rgb(0, 0, 0)",
+            "rgba(0, 0, 0, 0.5)",
+        ] {
+            assert_eq!(parse_text_fill(value), Ok(value.to_string()));
+        }
+    }
+
+    #[test]
+    fn parse_text_fill_rejects_what_could_break_out_of_the_style_attribute() {
+        for value in ["", "red; font-size: 99pt", "red\"", "url(x)<", "red'"] {
+            assert!(parse_text_fill(value).is_err(), "{}", value);
+        }
+    }
+}
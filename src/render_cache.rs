@@ -0,0 +1,140 @@
+use crate::render::{ImageFormat, RenderOptions};
+use lru::LruCache;
+use ordered_float::OrderedFloat;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use xxhash_rust::xxh3::xxh3_64;
+
+// everything that changes the bytes of an encoded page image
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    document_hash: u64,
+    page_index: usize,
+    scale: OrderedFloat<f32>,
+    format: ImageFormat,
+    quality: u8,
+    grayscale: bool,
+    // rgba
+    background: [u8; 4],
+    // x, y, width & height of the rendered region
+    clip: Option<[OrderedFloat<f32>; 4]>,
+    // scale of the render the image was resized from, none when pdfium rendered it at its own scale
+    downscaled_from: Option<OrderedFloat<f32>>,
+}
+
+// the most recently used encoded page images, shared by every request
+pub struct RenderCache {
+    images: LruCache<CacheKey, Arc<Vec<u8>>>,
+}
+
+impl RenderCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        RenderCache {
+            images: LruCache::new(capacity),
+        }
+    }
+}
+
+// the cache as seen by a single request, the document and the rendering options are fixed so
+// images are only looked up by page and scale
+#[derive(Clone)]
+pub struct DocumentCache {
+    cache: Arc<Mutex<RenderCache>>,
+    document_hash: u64,
+    format: ImageFormat,
+    quality: u8,
+    grayscale: bool,
+    background: [u8; 4],
+    clip: Option<[OrderedFloat<f32>; 4]>,
+    downscale_from: Option<OrderedFloat<f32>>,
+}
+
+impl DocumentCache {
+    pub fn new(
+        cache: Arc<Mutex<RenderCache>>,
+        pdf_data: &[u8],
+        render_options: &RenderOptions,
+    ) -> Self {
+        DocumentCache {
+            cache,
+            // a fast non cryptographic hash, accidental collisions are negligible but a client able
+            // to craft colliding PDFs could poison the renders of another document
+            document_hash: xxh3_64(pdf_data),
+            format: render_options.format,
+            quality: render_options.quality,
+            grayscale: render_options.grayscale,
+            background: [
+                render_options.background.red(),
+                render_options.background.green(),
+                render_options.background.blue(),
+                render_options.background.alpha(),
+            ],
+            clip: render_options
+                .clip
+                .map(|clip| [clip.x, clip.y, clip.width, clip.height].map(OrderedFloat)),
+            downscale_from: render_options.downscale_scale().map(OrderedFloat),
+        }
+    }
+
+    fn key(&self, page_index: usize, scale: f32) -> CacheKey {
+        CacheKey {
+            document_hash: self.document_hash,
+            page_index,
+            scale: OrderedFloat(scale),
+            format: self.format,
+            quality: self.quality,
+            grayscale: self.grayscale,
+            background: self.background,
+            clip: self.clip,
+            // the largest scale is rendered by pdfium even when downscaling
+            downscaled_from: self
+                .downscale_from
+                .filter(|from| *from != OrderedFloat(scale)),
+        }
+    }
+
+    // a poisoned lock only means another request panicked while holding it, the cache is a
+    // best effort so it's skipped instead of failing the request
+    pub fn get(&self, page_index: usize, scale: f32) -> Option<Arc<Vec<u8>>> {
+        let mut cache = self.cache.lock().ok()?;
+        cache.images.get(&self.key(page_index, scale)).cloned()
+    }
+
+    pub fn put(&self, page_index: usize, scale: f32, data: Arc<Vec<u8>>) {
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.images.put(self.key(page_index, scale), data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn document_cache(cache: &Arc<Mutex<RenderCache>>, params: &[(&str, &str)]) -> DocumentCache {
+        let params = params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        let render_options = RenderOptions::from_params(&params).unwrap();
+        DocumentCache::new(cache.clone(), b"%PDF-1.4", &render_options)
+    }
+
+    #[test]
+    fn downscaled_and_direct_renders_are_cached_apart() {
+        let cache = Arc::new(Mutex::new(RenderCache::new(NonZeroUsize::new(8).unwrap())));
+        let direct = document_cache(&cache, &[("scales", "1,2")]);
+        let downscaled = document_cache(&cache, &[("scales", "1,2"), ("downscale", "1")]);
+
+        downscaled.put(0, 1.0, Arc::new(b"resized".to_vec()));
+        assert!(direct.get(0, 1.0).is_none());
+        direct.put(0, 1.0, Arc::new(b"rendered".to_vec()));
+        assert_eq!(direct.get(0, 1.0).unwrap().as_slice(), b"rendered");
+        assert_eq!(downscaled.get(0, 1.0).unwrap().as_slice(), b"resized");
+
+        // pdfium renders the largest scale either way, both requests share it
+        direct.put(0, 2.0, Arc::new(b"largest".to_vec()));
+        assert_eq!(downscaled.get(0, 2.0).unwrap().as_slice(), b"largest");
+    }
+}
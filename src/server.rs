@@ -0,0 +1,3032 @@
+// the http side: routes, shared state, upload handling and the handlers of every endpoint
+use crate::error::{load_error, ApiError, ErrorResponse};
+use crate::fetch;
+use crate::links::{destination_page_index, extract_page_links, LinkInfo};
+use crate::openapi::{
+    ApiDoc, AttachmentParams, FileBody, PageParams, PageRangeParams, PdfUploadForm, PdfUploadsForm,
+    RenderParams, SearchParams, StreamParams, TextLayerParams, ThumbnailParams, UploadParams,
+};
+use crate::render::{
+    encode_image, is_flag_set, process_document, process_page, ImageEncoding, ImageFormat,
+    OutputMode, PageImage, PagePayload, RenderOptions,
+};
+use crate::render_cache::{DocumentCache, RenderCache};
+use crate::svg::page_svg;
+use crate::text::{
+    extract_page_text_groups, extract_page_words, rotation_degrees, PageTransform, RectResponse,
+    WordRect,
+};
+use crate::zip::ZipWriter;
+use axum::{
+    body::Body,
+    extract::{multipart::Field, DefaultBodyLimit, Multipart, Path, Query, Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware,
+    response::IntoResponse,
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use image::imageops::FilterType;
+use pdfium_render::prelude::*;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, TryLockError};
+use std::time::Duration;
+use tokio::sync::{mpsc, mpsc::error::SendTimeoutError, oneshot};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{DefaultOnResponse, TraceLayer},
+    LatencyUnit,
+};
+use tracing::Level;
+use utoipa::{OpenApi, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+use utoipa_swagger_ui::SwaggerUi;
+use uuid::Uuid;
+
+// size limits of the documents sent to /batch, the whole body can't be larger than MAX_BODY_SIZE anyway
+const MAX_BATCH_FILE_SIZE: usize = 100 * 1024 * 1024;
+const MAX_BATCH_SIZE: usize = MAX_BODY_SIZE;
+// number of documents /merge combines at most, their combined size is bounded by MAX_BATCH_SIZE
+const MAX_MERGE_FILES: usize = 50;
+// largest request body accepted by any endpoint
+const MAX_BODY_SIZE: usize = 250 * 1024 * 1024;
+// most outline entries returned by /bookmarks
+const MAX_BOOKMARKS: usize = 10_000;
+// how deep /attachments follows PDFs attached to attached PDFs
+const MAX_ATTACHMENT_DEPTH: usize = 4;
+// magic bytes every PDF starts with, and the marker closing it
+const PDF_HEADER: &[u8] = b"%PDF-";
+const PDF_EOF_MARKER: &[u8] = b"%%EOF";
+// how far from the start (resp. the end) of the file the header (resp. the end marker) is looked for
+const PDF_MARKER_WINDOW: usize = 1024;
+// response headers carrying the time spent on the pages, in milliseconds
+pub const EXTRACT_MS_HEADER: &str = "x-extract-ms";
+pub const RENDER_MS_HEADER: &str = "x-render-ms";
+// correlates a response with the logs of its request, taken from the request when the client (or a
+// proxy in front of the server) already set one
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// longest request id accepted from the client, anything longer gets replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+// bounding box of the thumbnails when max_width/max_height are not set, and the largest one allowed
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+const MAX_THUMBNAIL_SIZE: u32 = 2048;
+// thumbnails are rendered this many times larger than the box and downscaled for smoother edges
+const THUMBNAIL_SUPERSAMPLING: f32 = 2.0;
+// the thumbnail only depends on the uploaded bytes and the query, clients can keep it around
+const THUMBNAIL_CACHE_CONTROL: &str = "public, max-age=86400";
+// how long a streamed response waits for the client to take the previous page, the pdfium lock is
+// held meanwhile so a client that stops reading can't hold every other request up for longer
+const STREAM_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+// a blank 10x10 points single page PDF, loaded by /health to check pdfium actually works
+const HEALTH_CHECK_PDF: &[u8] = b"%PDF-1.4\n\
+1 0 obj\n\
+<</Type/Catalog/Pages 2 0 R>>\n\
+endobj\n\
+2 0 obj\n\
+<</Type/Pages/Kids[3 0 R]/Count 1>>\n\
+endobj\n\
+3 0 obj\n\
+<</Type/Page/Parent 2 0 R/MediaBox[0 0 10 10]>>\n\
+endobj\n\
+xref\n\
+0 4\n\
+0000000000 65535 f \n\
+0000000009 00000 n \n\
+0000000054 00000 n \n\
+0000000105 00000 n \n\
+trailer\n\
+<</Size 4/Root 1 0 R>>\n\
+startxref\n\
+168\n\
+%%EOF\n";
+// a PDF without a single page, its page tree has no kids
+#[cfg(test)]
+const EMPTY_DOCUMENT_PDF: &[u8] = b"%PDF-1.4\n\
+1 0 obj\n\
+<</Type/Catalog/Pages 2 0 R>>\n\
+endobj\n\
+2 0 obj\n\
+<</Type/Pages/Kids[]/Count 0>>\n\
+endobj\n\
+xref\n\
+0 3\n\
+0000000000 65535 f \n\
+0000000009 00000 n \n\
+0000000054 00000 n \n\
+trailer\n\
+<</Size 3/Root 1 0 R>>\n\
+startxref\n\
+100\n\
+%%EOF\n";
+
+// pdfium is bound once at startup and shared by every request, binding the library per request
+// would reload it every time. The library itself is not thread safe: pdfium-render only makes the
+// handle Send + Sync (through the `sync` feature) and a second instance would just wait on a process
+// wide lock until the first one is dropped. An `Arc<Pdfium>` alone would let two requests call into
+// the library at the same time, so requests take the mutex for as long as they hold a document
+// loaded from it
+#[derive(Clone)]
+pub struct AppState {
+    // set once the library is bound, the server already answers while main retries the binding
+    pub pdfium: Arc<OnceLock<Arc<Mutex<Pdfium>>>>,
+    // encoded page images, None when PDFIUM_CACHE_SIZE is 0
+    pub render_cache: Option<Arc<Mutex<RenderCache>>>,
+}
+
+impl AppState {
+    fn pdfium(&self) -> Result<Arc<Mutex<Pdfium>>, ApiError> {
+        self.pdfium
+            .get()
+            .cloned()
+            .ok_or(ApiError::PdfiumUnavailable)
+    }
+}
+
+// json mirror of PageImage, png buffers can't be embedded in json as raw bytes so they travel as base64
+#[derive(Serialize, ToSchema)]
+struct PageImageResponse {
+    scale: f32,
+    format: &'static str,
+    mime_type: &'static str,
+    data: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct EmbeddedImageResponse {
+    object_index: usize,
+    bounds: RectResponse,
+    mime_type: &'static str,
+    data: String,
+}
+
+// json mirror of PagePayload, tagged with the index of the page it was generated from
+#[derive(Serialize, ToSchema)]
+struct PagePayloadResponse {
+    page_index: usize,
+    // left out when the client asked for `output=images`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    svg_text: Option<String>,
+    // left out when the client asked for `output=svg`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<Vec<PageImageResponse>>,
+    // only with `output=text`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    // left out with `output=text`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    links: Option<Vec<LinkInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clip: Option<RectResponse>,
+    // only with `include_embedded_images`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    embedded_images: Vec<EmbeddedImageResponse>,
+}
+
+// the processed pages along with the total number of pages in the document
+struct DocumentPayload {
+    page_count: usize,
+    pages: Vec<PagePayload>,
+}
+
+// the pages to process, either optional 0-based inclusive bounds, missing bounds default to the
+// first/last page, or the list of `pages` which wins over the bounds
+#[derive(Clone, Default)]
+struct PageRange {
+    start: Option<usize>,
+    end: Option<usize>,
+    // 0-based inclusive ranges, in the order they were given
+    list: Option<Vec<RangeInclusive<usize>>>,
+    // pages of the list past the end of the document fail the request instead of being skipped
+    strict: bool,
+}
+
+impl PageRange {
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, ApiError> {
+        Ok(PageRange {
+            start: parse_page_param(params, "page_start")?,
+            end: parse_page_param(params, "page_end")?,
+            list: params
+                .get("pages")
+                .map(|value| parse_page_list(value))
+                .transpose()
+                .map_err(ApiError::InvalidParameter)?,
+            strict: is_flag_set(params, "strict"),
+        })
+    }
+}
+
+// the whole document as it is sent back to the client, pages are in document order and
+// page_count is the total in the document even when only a range of pages was processed
+#[derive(Serialize, ToSchema)]
+struct DocumentPayloadResponse {
+    page_count: usize,
+    pages: Vec<PagePayloadResponse>,
+}
+
+impl From<PageImage> for PageImageResponse {
+    fn from(image: PageImage) -> Self {
+        PageImageResponse {
+            scale: image.scale,
+            format: image.format,
+            mime_type: image.mime_type,
+            data: BASE64.encode(&*image.data),
+        }
+    }
+}
+
+impl From<DocumentPayload> for DocumentPayloadResponse {
+    fn from(payload: DocumentPayload) -> Self {
+        DocumentPayloadResponse {
+            page_count: payload.page_count,
+            pages: payload
+                .pages
+                .into_iter()
+                .map(PagePayloadResponse::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<PagePayload> for PagePayloadResponse {
+    fn from(payload: PagePayload) -> Self {
+        PagePayloadResponse {
+            page_index: payload.page_index,
+            svg_text: payload.svg_text,
+            images: payload
+                .images
+                .map(|images| images.into_iter().map(PageImageResponse::from).collect()),
+            text: payload.text,
+            links: payload.links,
+            clip: payload.clip,
+            embedded_images: payload
+                .embedded_images
+                .into_iter()
+                .map(|image| EmbeddedImageResponse {
+                    object_index: image.object_index,
+                    bounds: image.bounds,
+                    mime_type: ImageFormat::Png.mime_type(),
+                    data: BASE64.encode(&image.data),
+                })
+                .collect(),
+        }
+    }
+}
+
+// document level properties, every tag is optional since PDFs are free to leave them out
+#[derive(Serialize, ToSchema)]
+struct DocumentMetadata {
+    title: Option<String>,
+    author: Option<String>,
+    subject: Option<String>,
+    keywords: Option<String>,
+    creator: Option<String>,
+    producer: Option<String>,
+    creation_date: Option<String>,
+    modification_date: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct HealthResponse {
+    status: &'static str,
+}
+
+// `{"status": "ok", "pdfium": "bound"}` (or "busy", "binding"), or `{"status": "error", "detail": "..."}`
+// when the check failed
+#[derive(Serialize, ToSchema)]
+struct HealthCheckResponse {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pdfium: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PageCountResponse {
+    page_count: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DocumentMetadataResponse {
+    page_count: usize,
+    #[serde(flatten)]
+    metadata: DocumentMetadata,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PageSizeResponse {
+    page_index: usize,
+    width: f32,
+    height: f32,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PageDimensionsResponse {
+    page: usize,
+    width: f32,
+    height: f32,
+    // clockwise, in degrees
+    rotation: u16,
+}
+
+// a single annotation of a page (highlight, note, link, stamp, ...), with the bounds on the displayed page
+#[derive(Serialize, ToSchema)]
+struct AnnotationInfo {
+    annotation_type: String,
+    bounds: RectResponse,
+    contents: Option<String>,
+    // `#rrggbb`, missing when the annotation doesn't define one
+    color: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PageAnnotationsResponse {
+    page_index: usize,
+    annotations: Vec<AnnotationInfo>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DocumentAnnotationsResponse {
+    page_count: usize,
+    pages: Vec<PageAnnotationsResponse>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PageLinksResponse {
+    page_index: usize,
+    links: Vec<LinkInfo>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DocumentLinksResponse {
+    page_count: usize,
+    pages: Vec<PageLinksResponse>,
+}
+
+// a form field widget, checkboxes are `true`/`false` and checked radio buttons hold the value of
+// their group. The value is missing for buttons, signatures and empty fields
+#[derive(Serialize, ToSchema)]
+struct FormFieldResponse {
+    page: usize,
+    field_name: Option<String>,
+    field_type: String,
+    value: Option<String>,
+    bounds: RectResponse,
+}
+
+// an entry of the document outline, page_index is missing when it doesn't point inside the document
+#[derive(Serialize, ToSchema)]
+struct BookmarkResponse {
+    title: String,
+    page_index: Option<usize>,
+    #[schema(no_recursion)]
+    children: Vec<BookmarkResponse>,
+}
+
+// a search hit, spread over several rects when it spans lines or fonts
+#[derive(Serialize, ToSchema)]
+struct SearchMatchResponse {
+    page: usize,
+    text: String,
+    rects: Vec<RectResponse>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SearchResponse {
+    page_count: usize,
+    matches: Vec<SearchMatchResponse>,
+}
+
+// a search hit of `?group=page`, its box covers every rect of the hit
+#[derive(Serialize, ToSchema)]
+struct SearchHitResponse {
+    text: String,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+// the hits of a page, only the pages with at least one hit are listed
+#[derive(Serialize, ToSchema)]
+struct SearchPageResponse {
+    page: usize,
+    matches: Vec<SearchHitResponse>,
+}
+
+// every hit of the document by default, grouped by page with `?group=page`
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+enum SearchResults {
+    Matches(SearchResponse),
+    Pages(Vec<SearchPageResponse>),
+}
+
+// everything a viewer needs to lay out its skeleton before requesting any render
+#[derive(Serialize, ToSchema)]
+struct DocumentInfoResponse {
+    page_count: usize,
+    pages: Vec<PageSizeResponse>,
+    metadata: DocumentMetadata,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PageWordsResponse {
+    page_index: usize,
+    width: f32,
+    height: f32,
+    words: Vec<WordRect>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DocumentWordsResponse {
+    page_count: usize,
+    pages: Vec<PageWordsResponse>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PageTextResponse {
+    page: usize,
+    text: String,
+}
+
+// the result of one document of a batch, a document that fails doesn't fail the others
+#[derive(Serialize, ToSchema)]
+struct BatchDocumentResponse {
+    file_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<DocumentPayloadResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// a file attached to the document, with the processed pages of attached PDFs when `extract` is set
+#[derive(Serialize, ToSchema)]
+struct AttachmentResponse {
+    index: usize,
+    name: String,
+    // in bytes
+    size: usize,
+    is_pdf: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<DocumentPayloadResponse>,
+    // the files attached to this attachment, when it is a PDF
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[schema(no_recursion)]
+    attachments: Vec<AttachmentResponse>,
+    // an attachment that can't be read or processed doesn't fail the others
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// every route of the api along with the layers wrapping them
+pub fn router(state: AppState) -> Router {
+    // not logged, see below
+    let probes = OpenApiRouter::new()
+        .routes(routes!(health))
+        .routes(routes!(ready));
+    let (router, api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .routes(routes!(process_pdf))
+        .routes(routes!(process_batch))
+        .routes(routes!(process_single_page))
+        .routes(routes!(process_page_at))
+        .routes(routes!(page_text_layer))
+        .routes(routes!(page_thumbnail))
+        .routes(routes!(split_document))
+        .routes(routes!(merge_documents))
+        .routes(routes!(document_page_count))
+        .routes(routes!(document_metadata))
+        .routes(routes!(document_info))
+        .routes(routes!(document_dimensions))
+        .routes(routes!(search_document))
+        .routes(routes!(document_annotations))
+        .routes(routes!(document_bookmarks))
+        .routes(routes!(document_forms))
+        .routes(routes!(document_links))
+        .routes(routes!(document_attachments))
+        .routes(routes!(document_words))
+        .routes(routes!(document_text))
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        // only the path is recorded, the query string can hold the PDF password
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request<Body>| {
+                    tracing::info_span!(
+                        "request",
+                        method = %request.method(),
+                        path = %request.uri().path(),
+                        request_id = request
+                            .headers()
+                            .get(REQUEST_ID_HEADER)
+                            .and_then(|request_id| request_id.to_str().ok())
+                            .unwrap_or_default(),
+                        // filled in once the upload is read and the document loaded
+                        bytes = tracing::field::Empty,
+                        page_count = tracing::field::Empty,
+                    )
+                })
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(Level::INFO)
+                        .latency_unit(LatencyUnit::Millis),
+                ),
+        )
+        // merged after the layers so the probes and the docs skip the body limit and don't flood the logs
+        .merge(probes)
+        .split_for_parts();
+    router
+        .merge(SwaggerUi::new("/docs").url("/openapi.json", api))
+        .layer(compression_layer())
+        // outside the trace layer so the span already sees the id
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(middleware::map_request(drop_invalid_request_id))
+        .with_state(state)
+}
+
+// the X-Request-Id a client (or a proxy) sent is kept as long as it fits in a log line, anything
+// else is dropped so SetRequestIdLayer gives the request a uuid instead
+async fn drop_invalid_request_id(mut request: Request) -> Request {
+    let is_valid = |request_id: &HeaderValue| {
+        !request_id.is_empty()
+            && request_id.len() <= MAX_REQUEST_ID_LEN
+            && request_id.as_bytes().iter().all(|c| c.is_ascii_graphic())
+    };
+    if let Some(request_id) = request.headers().get(REQUEST_ID_HEADER) {
+        if !is_valid(request_id) {
+            request.headers_mut().remove(REQUEST_ID_HEADER);
+        }
+    }
+    request
+}
+
+// compresses the responses with gzip or brotli, whichever the client prefers, a dense svg text layer
+// is mostly repeated markup and shrinks a lot. The ndjson and zip streams are left
+// alone so they are still sent page by page, images and PDFs are already compressed
+fn compression_layer() -> CompressionLayer<impl Predicate> {
+    let predicate = DefaultPredicate::new()
+        .and(NotForContentType::const_new("application/x-ndjson"))
+        .and(NotForContentType::const_new("application/zip"))
+        .and(NotForContentType::const_new("application/pdf"));
+    CompressionLayer::new()
+        .no_deflate()
+        .no_zstd()
+        .compress_when(predicate)
+}
+
+// liveness probe, checks pdfium still works by loading a blank in-memory PDF. The probe never
+// waits for the shared instance: when a request holds it pdfium is busy working, which is as good a
+// sign of life, and while main is still binding the library the process is alive too (/ready covers that).
+// Both answer 200 with "busy" and "binding" in place of "bound", on top of the bound/503 pair the
+// probe was first specified with: a 503 there would have the orchestrator restart a pod that is
+// only busy with a long render or still starting
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "probes",
+    summary = "Liveness probe, loads a tiny blank PDF to check pdfium works unless pdfium is busy or still being bound",
+    responses(
+        (status = 200, description = "pdfium works (`bound`), is busy with a request (`busy`) or is still being bound at startup (`binding`)", body = HealthCheckResponse),
+        (status = 503, description = "pdfium failed to read the blank PDF", body = HealthCheckResponse)
+    )
+)]
+async fn health(State(state): State<AppState>) -> impl IntoResponse {
+    let Ok(pdfium) = state.pdfium() else {
+        return (
+            StatusCode::OK,
+            Json(HealthCheckResponse {
+                status: "ok",
+                pdfium: Some("binding"),
+                detail: None,
+            }),
+        );
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        let pdfium = match pdfium.try_lock() {
+            Ok(pdfium) => pdfium,
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Poisoned(_)) => {
+                return Err(ApiError::Internal(
+                    "the pdfium lock is poisoned".to_string(),
+                ))
+            }
+        };
+        let document = pdfium
+            .load_pdf_from_byte_slice(HEALTH_CHECK_PDF, None)
+            .map_err(load_error)?;
+        Ok(Some(document.pages().len()))
+    })
+    .await
+    .map_err(|err| ApiError::Internal(err.to_string()))
+    .and_then(|result| result);
+    match result {
+        Ok(None) => (
+            StatusCode::OK,
+            Json(HealthCheckResponse {
+                status: "ok",
+                pdfium: Some("busy"),
+                detail: None,
+            }),
+        ),
+        Ok(Some(1)) => (
+            StatusCode::OK,
+            Json(HealthCheckResponse {
+                status: "ok",
+                pdfium: Some("bound"),
+                detail: None,
+            }),
+        ),
+        Ok(Some(page_count)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(HealthCheckResponse {
+                status: "error",
+                pdfium: None,
+                detail: Some(format!(
+                    "the health check PDF was read with {} pages instead of 1",
+                    page_count
+                )),
+            }),
+        ),
+        Err(err) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(HealthCheckResponse {
+                status: "error",
+                pdfium: None,
+                detail: Some(err.to_string()),
+            }),
+        ),
+    }
+}
+
+// readiness probe, 503 until the pdfium library is bound. The shared instance is only checked, not
+// locked, so the probe doesn't wait behind the documents being processed
+#[utoipa::path(
+    get,
+    path = "/ready",
+    tag = "probes",
+    summary = "Readiness probe, 200 once the pdfium library is bound",
+    responses(
+        (status = 200, description = "Ready", body = HealthResponse),
+        (status = 503, description = "The pdfium library is not bound yet", body = HealthResponse)
+    )
+)]
+async fn ready(State(state): State<AppState>) -> impl IntoResponse {
+    match state.pdfium() {
+        Ok(_) => (StatusCode::OK, Json(HealthResponse { status: "ready" })),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(HealthResponse {
+                status: "the pdfium library is not available",
+            }),
+        ),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/process",
+    tag = "documents",
+    summary = "Renders the svg text layer and the images of every page",
+    params(UploadParams, PageRangeParams, RenderParams, TextLayerParams, StreamParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The processed pages, as a single document, a stream of pages, multipart parts or a zip archive", content(
+            (DocumentPayloadResponse = "application/json"),
+            (PagePayloadResponse = "application/x-ndjson"),
+            (FileBody = "multipart/mixed"),
+            (FileBody = "application/zip")
+        )),
+        ApiError
+    )
+)]
+async fn process_pdf(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let render_options = RenderOptions::from_params(&params)?;
+
+    // Extract the subset of pages to process, e.g. `?page_start=10&page_end=20` or `?pages=1-3,5`
+    let page_range = PageRange::from_params(&params)?;
+
+    // Extract whether the pages should be streamed as newline delimited json as soon as they are ready
+    let stream = is_flag_set(&params, "stream");
+    // Extract whether the pages should be sent as raw multipart/mixed parts instead of json
+    let multipart_response = is_flag_set(&params, "multipart");
+    if stream && multipart_response {
+        return Err(ApiError::InvalidParameter(
+            "stream and multipart can't be combined".to_string(),
+        ));
+    }
+
+    // `output=zip` is always streamed
+    let zip = render_options.output == OutputMode::Zip;
+    if zip && multipart_response {
+        return Err(ApiError::InvalidParameter(
+            "output=zip and multipart can't be combined".to_string(),
+        ));
+    }
+
+    // Extract the PDF file and the password needed to open encrypted PDFs from the multipart form
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    if zip {
+        let body = stream_document(
+            &state,
+            pdf_data,
+            password,
+            page_range,
+            render_options,
+            StreamFormat::Zip,
+        )
+        .await?;
+        return Ok((
+            [
+                (header::CONTENT_TYPE, "application/zip"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"pages.zip\"",
+                ),
+            ],
+            body,
+        )
+            .into_response());
+    }
+    if stream {
+        let body = stream_document(
+            &state,
+            pdf_data,
+            password,
+            page_range,
+            render_options,
+            StreamFormat::JsonLines,
+        )
+        .await?;
+        return Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response());
+    }
+
+    let render_cache = state.render_cache.clone();
+    let document_payload = with_pdfium(&state, move |pdfium| {
+        let cache = render_cache.map(|cache| DocumentCache::new(cache, &pdf_data, &render_options));
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let page_count = document.pages().len() as usize;
+        let page_indices = resolve_page_range(&page_range, page_count)?;
+
+        let mut pages = Vec::new();
+        process_document(&document, page_indices, &render_options, cache, |page| {
+            pages.push(page);
+            Ok(())
+        })?;
+
+        let (svg_ms, render_ms) = page_timings(&pages);
+        tracing::info!(
+            page_count,
+            processed_pages = pages.len(),
+            svg_ms,
+            render_ms,
+            "processed the document"
+        );
+        Ok(DocumentPayload { page_count, pages })
+    })
+    .await?;
+
+    let timing_headers = timing_headers(&document_payload.pages);
+    if multipart_response {
+        let boundary = multipart_boundary();
+        let content_type = format!("multipart/mixed; boundary={}", boundary);
+        let body = multipart_body(document_payload, &boundary);
+        return Ok((timing_headers, [(header::CONTENT_TYPE, content_type)], body).into_response());
+    }
+
+    // Send over the payload for every processed page
+    Ok((
+        timing_headers,
+        Json(DocumentPayloadResponse::from(document_payload)),
+    )
+        .into_response())
+}
+
+// time spent extracting the text & writing the svgs, and rendering & encoding the images of the pages
+fn page_timings(pages: &[PagePayload]) -> (u128, u128) {
+    pages.iter().fold((0, 0), |(svg_ms, render_ms), page| {
+        (
+            svg_ms + page.text_time.as_millis(),
+            render_ms + page.render_time.as_millis(),
+        )
+    })
+}
+
+// the page timings as response headers, so clients can tell what made a request slow without the logs
+fn timing_headers(pages: &[PagePayload]) -> [(HeaderName, String); 2] {
+    let (svg_ms, render_ms) = page_timings(pages);
+    [
+        (
+            HeaderName::from_static(EXTRACT_MS_HEADER),
+            svg_ms.to_string(),
+        ),
+        (
+            HeaderName::from_static(RENDER_MS_HEADER),
+            render_ms.to_string(),
+        ),
+    ]
+}
+
+// the boundary must not show up inside any part, the images are binary so make it long and random
+fn multipart_boundary() -> String {
+    format!("rust-pdf-{}", Uuid::new_v4().simple())
+}
+
+// writes every page as a `multipart/mixed` body: one svg part followed by one part per image, the
+// parts carry their page index (and scale) in the content disposition filename, e.g. `page-3@1.5x.png`
+fn multipart_body(payload: DocumentPayload, boundary: &str) -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    let mut write_part = |content_type: &str, filename: String, data: &[u8]| {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Type: {}\r\nContent-Disposition: inline; filename=\"{}\"\r\n\r\n",
+                boundary, content_type, filename
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    };
+
+    for page in payload.pages {
+        if let Some(svg_text) = page.svg_text {
+            write_part(
+                "image/svg+xml",
+                format!("page-{}.svg", page.page_index),
+                svg_text.as_bytes(),
+            );
+        }
+        if let Some(text) = page.text {
+            write_part(
+                "text/plain; charset=utf-8",
+                format!("page-{}.txt", page.page_index),
+                text.as_bytes(),
+            );
+        }
+        for image in page.images.into_iter().flatten() {
+            write_part(
+                image.mime_type,
+                format!("page-{}@{}x.{}", page.page_index, image.scale, image.format),
+                image.data.as_slice(),
+            );
+        }
+        for image in page.embedded_images {
+            write_part(
+                ImageFormat::Png.mime_type(),
+                format!("page-{}-object-{}.png", page.page_index, image.object_index),
+                &image.data,
+            );
+        }
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+// processes every PDF of the multipart form, e.g. to convert a whole folder in one request. The
+// rendering options, page range and password apply to every document
+#[utoipa::path(
+    post,
+    path = "/batch",
+    tag = "documents",
+    summary = "Processes every PDF of the form like /process",
+    params(UploadParams, PageRangeParams, RenderParams, TextLayerParams),
+    request_body(content = PdfUploadsForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = Vec<BatchDocumentResponse>),
+        ApiError
+    )
+)]
+async fn process_batch(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let render_options = RenderOptions::from_params(&params)?;
+    reject_zip_output(&render_options)?;
+    let page_range = PageRange::from_params(&params)?;
+    let (files, password) = read_pdf_uploads(&mut multipart, &params, None).await?;
+
+    let render_cache = state.render_cache.clone();
+    let documents = with_pdfium(&state, move |pdfium| {
+        let documents = files
+            .into_iter()
+            .map(|(file_name, pdf_data)| {
+                let result = process_batch_document(
+                    pdfium,
+                    pdf_data,
+                    password.as_deref(),
+                    &page_range,
+                    &render_options,
+                    render_cache.clone(),
+                );
+                match result {
+                    Ok(document) => BatchDocumentResponse {
+                        file_name,
+                        document: Some(DocumentPayloadResponse::from(document)),
+                        error: None,
+                    },
+                    Err(err) => BatchDocumentResponse {
+                        file_name,
+                        document: None,
+                        error: Some(err.to_string()),
+                    },
+                }
+            })
+            .collect::<Vec<BatchDocumentResponse>>();
+        Ok(documents)
+    })
+    .await?;
+
+    Ok(Json(documents))
+}
+
+fn process_batch_document(
+    pdfium: &Pdfium,
+    pdf_data: Vec<u8>,
+    password: Option<&str>,
+    page_range: &PageRange,
+    render_options: &RenderOptions,
+    render_cache: Option<Arc<Mutex<RenderCache>>>,
+) -> Result<DocumentPayload, ApiError> {
+    if pdf_data.is_empty() {
+        return Err(ApiError::EmptyFile);
+    }
+    if !is_valid_pdf(&pdf_data) {
+        return Err(ApiError::NotAPdf);
+    }
+    let cache = render_cache.map(|cache| DocumentCache::new(cache, &pdf_data, render_options));
+    let document = load_document(pdfium, pdf_data, password)?;
+    let page_count = document.pages().len() as usize;
+    let page_indices = resolve_page_range(page_range, page_count)?;
+
+    let mut pages = Vec::new();
+    process_document(&document, page_indices, render_options, cache, |page| {
+        pages.push(page);
+        Ok(())
+    })?;
+    Ok(DocumentPayload { page_count, pages })
+}
+
+// how the pages of a streamed response are written
+#[derive(Clone, Copy)]
+enum StreamFormat {
+    // one PagePayloadResponse per line
+    JsonLines,
+    // the files of every page in a zip archive
+    Zip,
+}
+
+// streams every page as soon as it is processed, the channel only holds a single page so rendering
+// waits for the client instead of buffering the whole document. The document is loaded and the page
+// range validated before the response starts, so those failures still get their proper status code.
+// Errors happening once the stream has started are sent as a final `{"error": ...}` line, a zip
+// archive is cut short instead and the error only shows up in the logs. A client that doesn't take a
+// page within STREAM_SEND_TIMEOUT gets its stream aborted, rendering holds the pdfium lock
+async fn stream_document(
+    state: &AppState,
+    pdf_data: Vec<u8>,
+    password: Option<String>,
+    page_range: PageRange,
+    render_options: RenderOptions,
+    format: StreamFormat,
+) -> Result<Body, ApiError> {
+    let (ready_sender, ready_receiver) = oneshot::channel::<Result<(), ApiError>>();
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>(1);
+    let pdfium = state.pdfium()?;
+    let render_cache = state.render_cache.clone();
+
+    let span = tracing::Span::current();
+    let runtime = tokio::runtime::Handle::current();
+
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        let cache = render_cache.map(|cache| DocumentCache::new(cache, &pdf_data, &render_options));
+        let pdfium = match lock_pdfium(&pdfium) {
+            Ok(pdfium) => pdfium,
+            Err(err) => {
+                let _ = ready_sender.send(Err(err));
+                return;
+            }
+        };
+        let prepared = load_document(&pdfium, pdf_data, password.as_deref()).and_then(|document| {
+            let page_indices = resolve_page_range(&page_range, document.pages().len() as usize)?;
+            Ok((document, page_indices))
+        });
+        let (document, page_indices) = match prepared {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                let _ = ready_sender.send(Err(err));
+                return;
+            }
+        };
+        if ready_sender.send(Ok(())).is_err() {
+            return;
+        }
+
+        let send = |chunk: Vec<u8>| {
+            runtime
+                .block_on(sender.send_timeout(chunk, STREAM_SEND_TIMEOUT))
+                .map_err(|err| match err {
+                    SendTimeoutError::Timeout(_) => {
+                        ApiError::Internal("the client stopped reading the stream".to_string())
+                    }
+                    SendTimeoutError::Closed(_) => {
+                        ApiError::Internal("the client went away".to_string())
+                    }
+                })
+        };
+        let mut zip = ZipWriter::default();
+        let result = process_document(&document, page_indices, &render_options, cache, |page| {
+            let chunk = match format {
+                StreamFormat::JsonLines => to_json_line(&PagePayloadResponse::from(page))?.into(),
+                StreamFormat::Zip => zip_page_entries(&mut zip, page)?,
+            };
+            send(chunk)
+        });
+        match (result, format) {
+            (Ok(()), StreamFormat::JsonLines) => {}
+            (Ok(()), StreamFormat::Zip) => {
+                if let Err(err) = zip.finish().and_then(send) {
+                    tracing::error!(error = %err, "the zip archive couldn't be finished");
+                }
+            }
+            (Err(err), StreamFormat::JsonLines) => {
+                let error = ErrorResponse {
+                    error: err.to_string(),
+                };
+                // without waiting, the client may be the reason the stream failed
+                if let Ok(line) = to_json_line(&error) {
+                    let _ = sender.try_send(line.into());
+                }
+            }
+            (Err(err), StreamFormat::Zip) => {
+                tracing::error!(error = %err, "the zip archive was cut short");
+            }
+        }
+    });
+
+    ready_receiver
+        .await
+        .map_err(|err| ApiError::Internal(err.to_string()))??;
+    Ok(Body::from_stream(
+        ReceiverStream::new(receiver).map(Ok::<Vec<u8>, Infallible>),
+    ))
+}
+
+// the files of a page in a zip archive: `page-3.svg`, one `page-3-1.5.png` per scale and a
+// `page-3-object-4.png` per embedded image. Unlike the multipart parts (`page-3@1.5x.png`) the scale
+// is a plain suffix of the name
+fn zip_page_entries(zip: &mut ZipWriter, page: PagePayload) -> Result<Vec<u8>, ApiError> {
+    let mut entries = Vec::new();
+    if let Some(svg_text) = page.svg_text {
+        entries.extend(zip.entry(
+            &format!("page-{}.svg", page.page_index),
+            svg_text.as_bytes(),
+        )?);
+    }
+    for image in page.images.into_iter().flatten() {
+        entries.extend(zip.entry(
+            &format!("page-{}-{}.{}", page.page_index, image.scale, image.format),
+            &image.data,
+        )?);
+    }
+    for image in page.embedded_images {
+        entries.extend(zip.entry(
+            &format!("page-{}-object-{}.png", page.page_index, image.object_index),
+            &image.data,
+        )?);
+    }
+    Ok(entries)
+}
+
+// zip archives are only streamed by `/process`
+fn reject_zip_output(render_options: &RenderOptions) -> Result<(), ApiError> {
+    if render_options.output == OutputMode::Zip {
+        return Err(ApiError::InvalidParameter(
+            "output=zip is only supported by /process".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn to_json_line<T: Serialize>(value: &T) -> Result<String, ApiError> {
+    let mut line =
+        serde_json::to_string(value).map_err(|err| ApiError::Internal(err.to_string()))?;
+    line.push('\n');
+    Ok(line)
+}
+
+// processes a single page, e.g. `/page?page=12`, without touching the rest of the document
+#[utoipa::path(
+    post,
+    path = "/page",
+    tag = "documents",
+    summary = "Processes a single page",
+    params(UploadParams, PageParams, RenderParams, TextLayerParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = PagePayloadResponse),
+        ApiError
+    )
+)]
+async fn process_single_page(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_index = parse_page_param(&params, "page")?.ok_or_else(|| {
+        ApiError::InvalidParameter("the page query parameter is required".to_string())
+    })?;
+    render_single_page(state, params, page_index, multipart).await
+}
+
+// same as `/page` with the page in the path, e.g. `/page/12`
+#[utoipa::path(
+    post,
+    path = "/page/{page}",
+    tag = "documents",
+    summary = "Processes a single page, given in the path",
+    params(("page" = usize, Path, description = "0-based index of the page"), UploadParams, RenderParams, TextLayerParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = PagePayloadResponse),
+        ApiError
+    )
+)]
+async fn process_page_at(
+    State(state): State<AppState>,
+    Path(page): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_index = page.trim().parse::<usize>().map_err(|_| {
+        ApiError::InvalidParameter(format!("page must be a page index, got `{}`", page))
+    })?;
+    render_single_page(state, params, page_index, multipart).await
+}
+
+async fn render_single_page(
+    state: AppState,
+    params: HashMap<String, String>,
+    page_index: usize,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let render_options = RenderOptions::from_params(&params)?;
+    reject_zip_output(&render_options)?;
+    let output = render_options.output;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let render_cache = state.render_cache.clone();
+    let page_payload = with_pdfium(&state, move |pdfium| {
+        let cache = render_cache.map(|cache| DocumentCache::new(cache, &pdf_data, &render_options));
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let pages = document.pages();
+        let page_count = pages.len() as usize;
+        if page_index >= page_count {
+            return Err(ApiError::PageNotFound {
+                page_index,
+                page_count,
+            });
+        }
+        let page = pages.get(page_index as PdfPageIndex)?;
+        process_page(&page, page_index, &render_options, cache)
+    })
+    .await?;
+
+    let timing_headers = timing_headers(std::slice::from_ref(&page_payload));
+    // a single page asked as svg only is sent as the svg document itself
+    if output == OutputMode::Svg {
+        let svg_text = page_payload.svg_text.unwrap_or_default();
+        return Ok((
+            timing_headers,
+            [(header::CONTENT_TYPE, "image/svg+xml")],
+            svg_text,
+        )
+            .into_response());
+    }
+    Ok((
+        timing_headers,
+        Json(PagePayloadResponse::from(page_payload)),
+    )
+        .into_response())
+}
+
+// renders a single page as a png fitting in `max_width` x `max_height`, e.g. for file browser previews
+#[utoipa::path(
+    post,
+    path = "/thumbnail",
+    tag = "documents",
+    summary = "Renders a page as a png fitting in a box",
+    params(UploadParams, PageParams, ThumbnailParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The thumbnail", body = FileBody, content_type = "image/png"),
+        ApiError
+    )
+)]
+async fn page_thumbnail(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_index = parse_page_param(&params, "page")?.unwrap_or(0);
+    let max_width = parse_thumbnail_size(&params, "max_width")?;
+    let max_height = parse_thumbnail_size(&params, "max_height")?;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let image = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let pages = document.pages();
+        let page_count = pages.len() as usize;
+        if page_index >= page_count {
+            return Err(ApiError::PageNotFound {
+                page_index,
+                page_count,
+            });
+        }
+        let page = pages.get(page_index as PdfPageIndex)?;
+        let page_width = page.width().value;
+        let page_height = page.height().value;
+
+        let scale = (max_width as f32 / page_width).min(max_height as f32 / page_height)
+            * THUMBNAIL_SUPERSAMPLING;
+        let render_config = PdfRenderConfig::new()
+            .set_format(PdfBitmapFormat::BGRA)
+            .set_reverse_byte_order(true)
+            .set_clear_color(PdfColor::WHITE)
+            .set_target_size(
+                ((page_width * scale) as i32).max(1),
+                ((page_height * scale) as i32).max(1),
+            );
+        let image = page.render_with_config(&render_config)?.as_image();
+        Ok(image)
+    })
+    .await?;
+
+    let png = tokio::task::spawn_blocking(move || {
+        let thumbnail = image.resize(max_width, max_height, FilterType::Lanczos3);
+        encode_image(thumbnail, ImageEncoding::png())
+    })
+    .await
+    .map_err(|err| ApiError::Internal(err.to_string()))??;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, ImageFormat::Png.mime_type()),
+            (header::CACHE_CONTROL, THUMBNAIL_CACHE_CONTROL),
+        ],
+        png,
+    ))
+}
+
+// returns only the svg text layer of a page, e.g. `/svg?page=12`, nothing gets rendered. Takes the
+// same text_fill/text_color and text grouping parameters as `/process`
+#[utoipa::path(
+    post,
+    path = "/svg",
+    tag = "documents",
+    summary = "Returns only the svg text layer of a page",
+    params(UploadParams, PageParams, TextLayerParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The svg text layer", body = String, content_type = "image/svg+xml"),
+        ApiError
+    )
+)]
+async fn page_text_layer(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_index = parse_page_param(&params, "page")?.ok_or_else(|| {
+        ApiError::InvalidParameter("the page query parameter is required".to_string())
+    })?;
+    let RenderOptions {
+        text_fill,
+        text_grouping,
+        ..
+    } = RenderOptions::from_params(&params)?;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let svg_text = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let pages = document.pages();
+        let page_count = pages.len() as usize;
+        if page_index >= page_count {
+            return Err(ApiError::PageNotFound {
+                page_index,
+                page_count,
+            });
+        }
+        let page = pages.get(page_index as PdfPageIndex)?;
+        let transform = PageTransform::new(&page)?;
+        let text_group_rects = extract_page_text_groups(&page, &transform, &text_grouping)?;
+        Ok(page_svg(
+            page.width().value,
+            page.height().value,
+            &text_group_rects,
+            &text_fill,
+        ))
+    })
+    .await?;
+
+    Ok(([(header::CONTENT_TYPE, "image/svg+xml")], svg_text))
+}
+
+// returns every page as its own single page PDF in a zip archive, `page_0001.pdf` being the first
+// page of the document. page_start/page_end or pages pick the pages to split out
+#[utoipa::path(
+    post,
+    path = "/split",
+    tag = "documents",
+    summary = "Splits the document into single page PDFs, returned in a zip archive",
+    params(UploadParams, PageRangeParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "A zip archive of single page PDFs", body = FileBody, content_type = "application/zip"),
+        ApiError
+    )
+)]
+async fn split_document(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_range = PageRange::from_params(&params)?;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let archive = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let page_indices = resolve_page_range(&page_range, document.pages().len() as usize)?;
+
+        let mut zip = ZipWriter::default();
+        let mut archive = Vec::new();
+        for page_index in page_indices {
+            let mut single_page = pdfium.create_new_pdf()?;
+            single_page.pages_mut().copy_page_from_document(
+                &document,
+                page_index as PdfPageIndex,
+                0,
+            )?;
+            let name = format!("page_{:04}.pdf", page_index + 1);
+            archive.extend(zip.entry(&name, &single_page.save_to_bytes()?)?);
+        }
+        archive.extend(zip.finish()?);
+        Ok(archive)
+    })
+    .await?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"pages.zip\"",
+            ),
+        ],
+        archive,
+    ))
+}
+
+// appends the pages of every uploaded PDF, in the order of the multipart form, to the first one.
+// The merged document is built on top of the first document so it keeps its metadata
+#[utoipa::path(
+    post,
+    path = "/merge",
+    tag = "documents",
+    summary = "Merges the uploaded PDFs, in the order of the form, into a single PDF keeping the metadata of the first one",
+    params(UploadParams),
+    request_body(content = PdfUploadsForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The merged PDF", body = FileBody, content_type = "application/pdf"),
+        ApiError
+    )
+)]
+async fn merge_documents(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let (files, password) =
+        read_pdf_uploads(&mut multipart, &params, Some(MAX_MERGE_FILES)).await?;
+    for (_, pdf_data) in &files {
+        if pdf_data.is_empty() {
+            return Err(ApiError::EmptyFile);
+        }
+        if !is_valid_pdf(pdf_data) {
+            return Err(ApiError::NotAPdf);
+        }
+    }
+
+    let merged = with_pdfium(&state, move |pdfium| {
+        let mut files = files.into_iter().map(|(_, pdf_data)| pdf_data);
+        let first = files.next().ok_or(ApiError::MissingFile)?;
+        let mut merged = load_document(pdfium, first, password.as_deref())?;
+        for pdf_data in files {
+            let document = load_document(pdfium, pdf_data, password.as_deref())?;
+            merged.pages_mut().append(&document)?;
+        }
+        Ok(merged.save_to_bytes()?)
+    })
+    .await?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/pdf"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"merged.pdf\"",
+            ),
+        ],
+        merged,
+    ))
+}
+
+// returns the number of pages, the pages themselves are never loaded
+#[utoipa::path(
+    post,
+    path = "/page_count",
+    tag = "documents",
+    summary = "Returns the number of pages",
+    params(UploadParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = PageCountResponse),
+        ApiError
+    )
+)]
+async fn document_page_count(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let page_count = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        Ok(PageCountResponse {
+            page_count: document.pages().len() as usize,
+        })
+    })
+    .await?;
+
+    Ok(Json(page_count))
+}
+
+// returns the document level properties and the page count without rendering anything
+#[utoipa::path(
+    post,
+    path = "/metadata",
+    tag = "documents",
+    summary = "Returns the document properties and the page count",
+    params(UploadParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = DocumentMetadataResponse),
+        ApiError
+    )
+)]
+async fn document_metadata(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let metadata = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        Ok(DocumentMetadataResponse {
+            page_count: document.pages().len() as usize,
+            metadata: read_document_metadata(&document),
+        })
+    })
+    .await?;
+
+    Ok(Json(metadata))
+}
+
+// returns the page count, the size of every page and the document metadata without rendering anything
+#[utoipa::path(
+    post,
+    path = "/info",
+    tag = "documents",
+    summary = "Returns the page sizes and the document properties",
+    params(UploadParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = DocumentInfoResponse),
+        ApiError
+    )
+)]
+async fn document_info(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let info = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let pages = document
+            .pages()
+            .iter()
+            .enumerate()
+            .map(|(page_index, page)| PageSizeResponse {
+                page_index,
+                width: page.width().value,
+                height: page.height().value,
+            })
+            .collect::<Vec<PageSizeResponse>>();
+
+        Ok(DocumentInfoResponse {
+            page_count: pages.len(),
+            pages,
+            metadata: read_document_metadata(&document),
+        })
+    })
+    .await?;
+
+    Ok(Json(info))
+}
+
+// returns the words of every page along with their bounding boxes, without rendering any image
+#[utoipa::path(
+    post,
+    path = "/words",
+    tag = "documents",
+    summary = "Returns the words of every page with their boxes",
+    params(UploadParams, PageRangeParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = DocumentWordsResponse),
+        ApiError
+    )
+)]
+async fn document_words(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_range = PageRange::from_params(&params)?;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let words = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let pages = document.pages();
+        let page_count = pages.len() as usize;
+
+        let pages = resolve_page_range(&page_range, page_count)?
+            .into_iter()
+            .map(|page_index| {
+                let page = pages.get(page_index as PdfPageIndex)?;
+                let transform = PageTransform::new(&page)?;
+                Ok(PageWordsResponse {
+                    page_index,
+                    width: page.width().value,
+                    height: page.height().value,
+                    words: extract_page_words(&page, &transform)?,
+                })
+            })
+            .collect::<Result<Vec<PageWordsResponse>, ApiError>>()?;
+
+        Ok(DocumentWordsResponse { page_count, pages })
+    })
+    .await?;
+
+    Ok(Json(words))
+}
+
+// returns the plain text of every page, nothing gets rendered so it's much cheaper than `/process`
+#[utoipa::path(
+    post,
+    path = "/text",
+    tag = "documents",
+    summary = "Returns the plain text of every page",
+    params(UploadParams, PageRangeParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = Vec<PageTextResponse>),
+        ApiError
+    )
+)]
+async fn document_text(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_range = PageRange::from_params(&params)?;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let pages = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let pages = document.pages();
+
+        let page_texts = resolve_page_range(&page_range, pages.len() as usize)?
+            .into_iter()
+            .map(|page_index| {
+                let page = pages.get(page_index as PdfPageIndex)?;
+                let text = page.text()?.all();
+                Ok(PageTextResponse {
+                    page: page_index,
+                    text,
+                })
+            })
+            .collect::<Result<Vec<PageTextResponse>, ApiError>>()?;
+        Ok(page_texts)
+    })
+    .await?;
+
+    Ok(Json(pages))
+}
+
+// returns the displayed size and the rotation of every page, nothing is extracted nor rendered
+#[utoipa::path(
+    post,
+    path = "/dimensions",
+    tag = "documents",
+    summary = "Returns the displayed size and rotation of every page",
+    params(UploadParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = Vec<PageDimensionsResponse>),
+        ApiError
+    )
+)]
+async fn document_dimensions(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let dimensions = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let dimensions = document
+            .pages()
+            .iter()
+            .enumerate()
+            .map(|(page_index, page)| {
+                Ok(PageDimensionsResponse {
+                    page: page_index,
+                    width: page.width().value,
+                    height: page.height().value,
+                    rotation: rotation_degrees(page.rotation()?),
+                })
+            })
+            .collect::<Result<Vec<PageDimensionsResponse>, ApiError>>()?;
+        Ok(dimensions)
+    })
+    .await?;
+
+    Ok(Json(dimensions))
+}
+
+// finds every occurrence of `q` in the document, e.g. `/search?q=photosynthesis&case_sensitive=1`
+#[utoipa::path(
+    post,
+    path = "/search",
+    tag = "documents",
+    summary = "Finds every occurrence of a text",
+    params(UploadParams, SearchParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK, the matches grouped by page with `group=page`", body = SearchResults),
+        ApiError
+    )
+)]
+async fn search_document(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let query = params
+        .get("q")
+        .filter(|query| !query.is_empty())
+        .cloned()
+        .ok_or_else(|| {
+            ApiError::InvalidParameter("the q query parameter is required".to_string())
+        })?;
+    let search_options = PdfSearchOptions::new().match_case(is_flag_set(&params, "case_sensitive"));
+    let group_by_page = match params.get("group").map(|group| group.trim()) {
+        None => false,
+        Some(group) if group.eq_ignore_ascii_case("page") => true,
+        Some(group) => {
+            return Err(ApiError::InvalidParameter(format!(
+                "unsupported group `{}`, expected page",
+                group
+            )))
+        }
+    };
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let search_results = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let mut matches = Vec::new();
+        for (page_index, page) in document.pages().iter().enumerate() {
+            matches.extend(search_page(&page, page_index, &query, &search_options)?);
+        }
+        Ok(SearchResponse {
+            page_count: document.pages().len() as usize,
+            matches,
+        })
+    })
+    .await?;
+
+    if group_by_page {
+        return Ok(Json(SearchResults::Pages(group_matches_by_page(
+            search_results.matches,
+        ))));
+    }
+    Ok(Json(SearchResults::Matches(search_results)))
+}
+
+// the matches come page after page, each one becomes a single hit boxing all of its rects
+fn group_matches_by_page(matches: Vec<SearchMatchResponse>) -> Vec<SearchPageResponse> {
+    let mut pages: Vec<SearchPageResponse> = Vec::new();
+    for search_match in matches {
+        if search_match.rects.is_empty() {
+            continue;
+        }
+        let left = search_match
+            .rects
+            .iter()
+            .map(|rect| rect.x)
+            .fold(f32::MAX, f32::min);
+        let top = search_match
+            .rects
+            .iter()
+            .map(|rect| rect.y)
+            .fold(f32::MAX, f32::min);
+        let right = search_match
+            .rects
+            .iter()
+            .map(|rect| rect.x + rect.width)
+            .fold(f32::MIN, f32::max);
+        let bottom = search_match
+            .rects
+            .iter()
+            .map(|rect| rect.y + rect.height)
+            .fold(f32::MIN, f32::max);
+        let hit = SearchHitResponse {
+            text: search_match.text,
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        };
+        match pages.last_mut() {
+            Some(page) if page.page == search_match.page => page.matches.push(hit),
+            _ => pages.push(SearchPageResponse {
+                page: search_match.page,
+                matches: vec![hit],
+            }),
+        }
+    }
+    pages
+}
+
+fn search_page(
+    page: &PdfPage<'_>,
+    page_index: usize,
+    query: &str,
+    search_options: &PdfSearchOptions,
+) -> Result<Vec<SearchMatchResponse>, ApiError> {
+    let transform = PageTransform::new(page)?;
+    let text = page.text()?;
+    let search = text.search(query, search_options)?;
+
+    let matches = search
+        .iter(PdfSearchDirection::SearchForward)
+        .map(|segments| {
+            let mut search_match = SearchMatchResponse {
+                page: page_index,
+                text: String::new(),
+                rects: Vec::new(),
+            };
+            for segment in segments.iter() {
+                let (x, y, width, height) = transform.rect_to_display(&segment.bounds());
+                search_match.text.push_str(&segment.text());
+                search_match.rects.push(RectResponse {
+                    x,
+                    y,
+                    width,
+                    height,
+                });
+            }
+            search_match
+        })
+        .collect();
+    Ok(matches)
+}
+
+// returns the annotations of every page, `page_start`/`page_end` limit the pages like in `/process`
+#[utoipa::path(
+    post,
+    path = "/annotations",
+    tag = "documents",
+    summary = "Returns the annotations of every page",
+    params(UploadParams, PageRangeParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = DocumentAnnotationsResponse),
+        ApiError
+    )
+)]
+async fn document_annotations(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_range = PageRange::from_params(&params)?;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let annotations = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let pages = document.pages();
+        let page_count = pages.len() as usize;
+
+        let pages = resolve_page_range(&page_range, page_count)?
+            .into_iter()
+            .map(|page_index| {
+                let page = pages.get(page_index as PdfPageIndex)?;
+                Ok(PageAnnotationsResponse {
+                    page_index,
+                    annotations: extract_page_annotations(&page)?,
+                })
+            })
+            .collect::<Result<Vec<PageAnnotationsResponse>, ApiError>>()?;
+
+        Ok(DocumentAnnotationsResponse { page_count, pages })
+    })
+    .await?;
+
+    Ok(Json(annotations))
+}
+
+fn extract_page_annotations(page: &PdfPage<'_>) -> Result<Vec<AnnotationInfo>, ApiError> {
+    let transform = PageTransform::new(page)?;
+    page.annotations()
+        .iter()
+        .map(|annotation| {
+            let bounds = annotation.bounds()?;
+            let (x, y, width, height) = transform.rect_to_display(&bounds);
+            Ok(AnnotationInfo {
+                annotation_type: format!("{:?}", annotation.annotation_type()),
+                bounds: RectResponse {
+                    x,
+                    y,
+                    width,
+                    height,
+                },
+                contents: annotation
+                    .contents()
+                    .filter(|contents| !contents.is_empty()),
+                color: annotation
+                    .stroke_color()
+                    .ok()
+                    .map(|color| format!("#{}", color.to_hex().to_ascii_lowercase())),
+            })
+        })
+        .collect()
+}
+
+// lists the files attached to the document, e.g. the exhibits of a legal filing. With `?extract=true`
+// every attached PDF is processed like `/process` would (the rendering options apply to all of them)
+// and the PDFs attached to it are listed in turn, up to MAX_ATTACHMENT_DEPTH levels deep
+#[utoipa::path(
+    post,
+    path = "/attachments",
+    tag = "documents",
+    summary = "Lists the files attached to the document",
+    params(UploadParams, AttachmentParams, RenderParams, TextLayerParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = Vec<AttachmentResponse>),
+        ApiError
+    )
+)]
+async fn document_attachments(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let extract = is_flag_set(&params, "extract");
+    let render_options = RenderOptions::from_params(&params)?;
+    reject_zip_output(&render_options)?;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let render_cache = state.render_cache.clone();
+    let attachments = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let render_options = extract.then_some(&render_options);
+        Ok(read_attachments(
+            pdfium,
+            &document,
+            render_options,
+            render_cache,
+            0,
+        ))
+    })
+    .await?;
+
+    Ok(Json(attachments))
+}
+
+// the attachments of a document, the attached PDFs are only opened when there are render options
+fn read_attachments(
+    pdfium: &Pdfium,
+    document: &PdfDocument<'_>,
+    render_options: Option<&RenderOptions>,
+    render_cache: Option<Arc<Mutex<RenderCache>>>,
+    depth: usize,
+) -> Vec<AttachmentResponse> {
+    document
+        .attachments()
+        .iter()
+        .enumerate()
+        .map(|(index, attachment)| {
+            let mut response = AttachmentResponse {
+                index,
+                name: attachment.name(),
+                size: attachment.len(),
+                is_pdf: false,
+                document: None,
+                attachments: Vec::new(),
+                error: None,
+            };
+            let data = match attachment.save_to_bytes() {
+                Ok(data) => data,
+                Err(err) => {
+                    response.error = Some(ApiError::from(err).to_string());
+                    return response;
+                }
+            };
+            response.is_pdf = is_valid_pdf(&data);
+            let Some(render_options) = render_options.filter(|_| response.is_pdf) else {
+                return response;
+            };
+
+            let cache = render_cache
+                .clone()
+                .map(|cache| DocumentCache::new(cache, &data, render_options));
+            // not through load_document, the page count on the span is the one of the uploaded PDF
+            let result = pdfium
+                .load_pdf_from_byte_vec(data, None)
+                .map_err(load_error)
+                .and_then(|attached| {
+                    let page_count = attached.pages().len() as usize;
+                    let mut pages = Vec::new();
+                    process_document(
+                        &attached,
+                        (0..page_count).collect(),
+                        render_options,
+                        cache,
+                        |page| {
+                            pages.push(page);
+                            Ok(())
+                        },
+                    )?;
+                    if depth + 1 < MAX_ATTACHMENT_DEPTH {
+                        response.attachments = read_attachments(
+                            pdfium,
+                            &attached,
+                            Some(render_options),
+                            render_cache.clone(),
+                            depth + 1,
+                        );
+                    }
+                    Ok(DocumentPayload { page_count, pages })
+                });
+            match result {
+                Ok(payload) => response.document = Some(DocumentPayloadResponse::from(payload)),
+                Err(err) => response.error = Some(err.to_string()),
+            }
+            response
+        })
+        .collect()
+}
+
+// returns the links of every page without rendering anything, the same ones `/process` returns
+// along with the page images
+#[utoipa::path(
+    post,
+    path = "/links",
+    tag = "documents",
+    summary = "Returns the links of every page",
+    params(UploadParams, PageRangeParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = DocumentLinksResponse),
+        ApiError
+    )
+)]
+async fn document_links(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_range = PageRange::from_params(&params)?;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let links = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let pages = document.pages();
+        let page_count = pages.len() as usize;
+
+        let pages = resolve_page_range(&page_range, page_count)?
+            .into_iter()
+            .map(|page_index| {
+                let page = pages.get(page_index as PdfPageIndex)?;
+                Ok(PageLinksResponse {
+                    page_index,
+                    links: extract_page_links(&page, &PageTransform::new(&page)?),
+                })
+            })
+            .collect::<Result<Vec<PageLinksResponse>, ApiError>>()?;
+
+        Ok(DocumentLinksResponse { page_count, pages })
+    })
+    .await?;
+
+    Ok(Json(links))
+}
+
+// returns the form fields of every page in page order, `page_start`/`page_end` limit the pages like
+// in `/process`
+#[utoipa::path(
+    post,
+    path = "/forms",
+    tag = "documents",
+    summary = "Returns the form fields of every page",
+    params(UploadParams, PageRangeParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = Vec<FormFieldResponse>),
+        ApiError
+    )
+)]
+async fn document_forms(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let page_range = PageRange::from_params(&params)?;
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let fields = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let pages = document.pages();
+        let mut fields = Vec::new();
+        for page_index in resolve_page_range(&page_range, pages.len() as usize)? {
+            let page = pages.get(page_index as PdfPageIndex)?;
+            fields.extend(extract_page_form_fields(&page, page_index)?);
+        }
+        Ok(fields)
+    })
+    .await?;
+
+    Ok(Json(fields))
+}
+
+// every widget is returned on its own, the radio buttons of a group share their field name
+fn extract_page_form_fields(
+    page: &PdfPage<'_>,
+    page_index: usize,
+) -> Result<Vec<FormFieldResponse>, ApiError> {
+    let transform = PageTransform::new(page)?;
+    let mut fields = Vec::new();
+    for annotation in page.annotations().iter() {
+        let Some(field) = annotation.as_form_field() else {
+            continue;
+        };
+        let value = if let Some(field) = field.as_text_field() {
+            field.value()
+        } else if let Some(field) = field.as_checkbox_field() {
+            Some(field.is_checked().unwrap_or(false).to_string())
+        } else if let Some(field) = field.as_radio_button_field() {
+            if field.is_checked().unwrap_or(false) {
+                field.group_value()
+            } else {
+                Some(false.to_string())
+            }
+        } else if let Some(field) = field.as_combo_box_field() {
+            field.value()
+        } else if let Some(field) = field.as_list_box_field() {
+            field.value()
+        } else {
+            None
+        };
+
+        let (x, y, width, height) = transform.rect_to_display(&annotation.bounds()?);
+        fields.push(FormFieldResponse {
+            page: page_index,
+            field_name: field.name(),
+            field_type: format!("{:?}", field.field_type()),
+            value: value.filter(|value| !value.is_empty()),
+            bounds: RectResponse {
+                x,
+                y,
+                width,
+                height,
+            },
+        });
+    }
+    Ok(fields)
+}
+
+// returns the outline of the document as a tree, an empty array when it has none
+#[utoipa::path(
+    post,
+    path = "/bookmarks",
+    tag = "documents",
+    summary = "Returns the outline of the document",
+    params(UploadParams),
+    request_body(content = PdfUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "OK", body = Vec<BookmarkResponse>),
+        ApiError
+    )
+)]
+async fn document_bookmarks(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+    let PdfUpload { pdf_data, password } = read_pdf_upload(&mut multipart, &params).await?;
+
+    let bookmarks = with_pdfium(&state, move |pdfium| {
+        let document = load_document(pdfium, pdf_data, password.as_deref())?;
+        let bookmarks = document.bookmarks();
+        let mut remaining = MAX_BOOKMARKS;
+        Ok(read_bookmark_siblings(bookmarks.root(), &mut remaining))
+    })
+    .await?;
+
+    Ok(Json(bookmarks))
+}
+
+// walks `first` and its next siblings along with all their children. Outlines are linked lists in the
+// file, a broken one can loop forever so the walk stops after `remaining` entries
+fn read_bookmark_siblings(
+    first: Option<PdfBookmark<'_>>,
+    remaining: &mut usize,
+) -> Vec<BookmarkResponse> {
+    let mut siblings = Vec::new();
+    let mut current = first;
+    while let Some(bookmark) = current {
+        if *remaining == 0 {
+            break;
+        }
+        *remaining -= 1;
+
+        siblings.push(BookmarkResponse {
+            title: bookmark.title().unwrap_or_default(),
+            page_index: destination_page_index(bookmark.destination(), bookmark.action()),
+            children: read_bookmark_siblings(bookmark.first_child(), remaining),
+        });
+        current = bookmark.next_sibling();
+    }
+    siblings
+}
+
+fn read_document_metadata(document: &PdfDocument<'_>) -> DocumentMetadata {
+    let metadata = document.metadata();
+    let tag = |tag_type| metadata.get(tag_type).map(|tag| tag.value().to_string());
+
+    DocumentMetadata {
+        title: tag(PdfDocumentMetadataTagType::Title),
+        author: tag(PdfDocumentMetadataTagType::Author),
+        subject: tag(PdfDocumentMetadataTagType::Subject),
+        keywords: tag(PdfDocumentMetadataTagType::Keywords),
+        creator: tag(PdfDocumentMetadataTagType::Creator),
+        producer: tag(PdfDocumentMetadataTagType::Producer),
+        creation_date: tag(PdfDocumentMetadataTagType::CreationDate),
+        modification_date: tag(PdfDocumentMetadataTagType::ModificationDate),
+    }
+}
+
+// the PDF sent to an endpoint along with the password needed to open it, if any
+struct PdfUpload {
+    pdf_data: Vec<u8>,
+    password: Option<String>,
+}
+
+// file uploads carry a filename, a field named `file` is taken as one even without it. Any other
+// field without a filename is a plain text field, not a PDF
+fn is_file_field(field: &Field<'_>) -> bool {
+    field.file_name().is_some() || field.name() == Some("file")
+}
+
+// reads the uploaded PDF out of the multipart form, the last file field wins. The password can be sent
+// as a `password` form field, which keeps it out of the url (and so out of access logs), or as a query
+// parameter. The form field wins when both are given. Without a file the PDF is fetched from the `url`
+// form field or query parameter instead, and checked just like an upload
+async fn read_pdf_upload(
+    multipart: &mut Multipart,
+    params: &HashMap<String, String>,
+) -> Result<PdfUpload, ApiError> {
+    let max_size = parse_max_size(params)?;
+    let mut pdf_data: Option<Vec<u8>> = None;
+    let mut password = params.get("password").cloned();
+    let mut url = params.get("url").cloned();
+    while let Some(field) = multipart.next_field().await? {
+        if field.name() == Some("password") {
+            password = Some(field.text().await?);
+            continue;
+        }
+        if field.name() == Some("url") && field.file_name().is_none() {
+            url = Some(field.text().await?);
+            continue;
+        }
+        if !is_file_field(&field) {
+            continue;
+        }
+        let data = field.bytes().await?;
+        if let Some(max_size) = max_size.filter(|max_size| data.len() > *max_size) {
+            return Err(ApiError::PayloadTooLarge(format!(
+                "the file is larger than the {} bytes allowed by max_size_mb",
+                max_size
+            )));
+        }
+        pdf_data = Some(data.to_vec());
+    }
+    let pdf_data = match (pdf_data, url) {
+        (Some(pdf_data), _) => pdf_data,
+        (None, Some(url)) => fetch::fetch_pdf(&url, max_size).await?,
+        (None, None) => return Err(ApiError::MissingFile),
+    };
+    tracing::Span::current().record("bytes", pdf_data.len());
+    if pdf_data.is_empty() {
+        return Err(ApiError::EmptyFile);
+    }
+    if !is_valid_pdf(&pdf_data) {
+        return Err(ApiError::NotAPdf);
+    }
+    Ok(PdfUpload { pdf_data, password })
+}
+
+// reads every file of the multipart form along with its file name (or field name when it has none),
+// the headers are checked per document so a single bad file doesn't fail the whole batch. Past
+// `max_files` files the form is rejected as soon as the next file field shows up, before reading it
+async fn read_pdf_uploads(
+    multipart: &mut Multipart,
+    params: &HashMap<String, String>,
+    max_files: Option<usize>,
+) -> Result<(Vec<(String, Vec<u8>)>, Option<String>), ApiError> {
+    let max_file_size = parse_max_size(params)?.map_or(MAX_BATCH_FILE_SIZE, |max_size| {
+        max_size.min(MAX_BATCH_FILE_SIZE)
+    });
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut password = params.get("password").cloned();
+    let mut total_size = 0;
+    while let Some(field) = multipart.next_field().await? {
+        if field.name() == Some("password") {
+            password = Some(field.text().await?);
+            continue;
+        }
+        if !is_file_field(&field) {
+            continue;
+        }
+        if let Some(max_files) = max_files.filter(|max_files| files.len() >= *max_files) {
+            return Err(ApiError::InvalidParameter(format!(
+                "at most {} PDFs can be sent at once",
+                max_files
+            )));
+        }
+        let file_name = field
+            .file_name()
+            .or(field.name())
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("file-{}", files.len()));
+        let data = field.bytes().await?;
+
+        if data.len() > max_file_size {
+            return Err(ApiError::PayloadTooLarge(format!(
+                "{} is larger than the {} bytes allowed per file",
+                file_name, max_file_size
+            )));
+        }
+        total_size += data.len();
+        if total_size > MAX_BATCH_SIZE {
+            return Err(ApiError::PayloadTooLarge(format!(
+                "the files are larger than the {} bytes allowed per batch",
+                MAX_BATCH_SIZE
+            )));
+        }
+        files.push((file_name, data.to_vec()));
+    }
+    if files.is_empty() {
+        return Err(ApiError::MissingFile);
+    }
+    tracing::Span::current().record(
+        "bytes",
+        files.iter().map(|(_, data)| data.len()).sum::<usize>(),
+    );
+    Ok((files, password))
+}
+
+// a lower size limit for the uploaded files a client can opt into, e.g. `?max_size_mb=10`. The
+// router wide limit still applies on top of it
+fn parse_max_size(params: &HashMap<String, String>) -> Result<Option<usize>, ApiError> {
+    params
+        .get("max_size_mb")
+        .map(|value| {
+            value
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|max_size_mb| *max_size_mb > 0)
+                .map(|max_size_mb| max_size_mb.saturating_mul(1024 * 1024))
+                .ok_or_else(|| {
+                    ApiError::InvalidParameter(format!(
+                        "max_size_mb must be a positive number of megabytes, got `{}`",
+                        value
+                    ))
+                })
+        })
+        .transpose()
+}
+
+// cheap sanity check before handing the bytes to pdfium. Readers are expected to find the `%PDF-`
+// header anywhere in the first 1024 bytes since some generators put junk before it, and the `%%EOF`
+// marker can likewise be followed by trailing garbage
+fn is_valid_pdf(data: &[u8]) -> bool {
+    let contains = |haystack: &[u8], needle: &[u8]| {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    };
+    contains(&data[..data.len().min(PDF_MARKER_WINDOW)], PDF_HEADER)
+        && contains(
+            &data[data.len().saturating_sub(PDF_MARKER_WINDOW)..],
+            PDF_EOF_MARKER,
+        )
+}
+
+// pdfium calls are blocking, so `f` runs on the blocking pool while holding the pdfium lock
+async fn with_pdfium<T, F>(state: &AppState, f: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce(&Pdfium) -> Result<T, ApiError> + Send + 'static,
+{
+    let pdfium = state.pdfium()?;
+    // spans don't follow the work to the blocking pool on their own
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        f(&*lock_pdfium(&pdfium)?)
+    })
+    .await
+    .map_err(|err| ApiError::Internal(err.to_string()))?
+}
+
+fn lock_pdfium(pdfium: &Mutex<Pdfium>) -> Result<MutexGuard<'_, Pdfium>, ApiError> {
+    pdfium
+        .lock()
+        .map_err(|_| ApiError::Internal("the pdfium lock is poisoned".to_string()))
+}
+
+fn load_document<'a>(
+    pdfium: &'a Pdfium,
+    pdf_data: Vec<u8>,
+    password: Option<&str>,
+) -> Result<PdfDocument<'a>, ApiError> {
+    let document = pdfium
+        .load_pdf_from_byte_vec(pdf_data, password)
+        .map_err(load_error)?;
+    tracing::Span::current().record("page_count", document.pages().len());
+    Ok(document)
+}
+
+// parses an optional 0-based page index query parameter
+fn parse_page_param(
+    params: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<usize>, ApiError> {
+    params
+        .get(name)
+        .map(|value| {
+            value.trim().parse::<usize>().map_err(|_| {
+                ApiError::InvalidParameter(format!(
+                    "{} must be a page index, got `{}`",
+                    name, value
+                ))
+            })
+        })
+        .transpose()
+}
+
+// checks the requested pages against the document and returns the page indices to iterate, in
+// document order and without duplicates
+fn resolve_page_range(page_range: &PageRange, page_count: usize) -> Result<Vec<usize>, ApiError> {
+    if let Some(list) = &page_range.list {
+        let mut page_indices = BTreeSet::new();
+        for range in list {
+            if page_range.strict && *range.end() >= page_count {
+                return Err(ApiError::InvalidParameter(format!(
+                    "page {} is out of range, the document has {} pages",
+                    range.end() + 1,
+                    page_count
+                )));
+            }
+            // bounded by the page count first, a huge range past the end costs nothing
+            page_indices.extend(*range.start()..(range.end() + 1).min(page_count));
+        }
+        return Ok(page_indices.into_iter().collect());
+    }
+
+    if page_count == 0 && page_range.start.is_none() && page_range.end.is_none() {
+        return Ok(Vec::new());
+    }
+    let page_start = page_range.start.unwrap_or(0);
+    let page_end = page_range.end.unwrap_or(page_count.saturating_sub(1));
+    if page_start > page_end || page_end >= page_count {
+        return Err(ApiError::InvalidParameter(format!(
+            "invalid page range {}..={}, the document has {} pages (0-based, page_start <= page_end < {})",
+            page_start, page_end, page_count, page_count
+        )));
+    }
+    Ok((page_start..page_end + 1).collect())
+}
+
+// a 1-based list of pages and inclusive ranges, e.g. `1-3,5,10-12`
+fn parse_page_list(value: &str) -> Result<Vec<RangeInclusive<usize>>, String> {
+    let parse_page = |raw: &str| {
+        raw.trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|page| *page > 0)
+            .ok_or_else(|| {
+                format!(
+                    "invalid page `{}` in pages, pages are numbered from 1",
+                    raw.trim()
+                )
+            })
+    };
+    value
+        .split(',')
+        .map(|part| {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (parse_page(first)?, parse_page(last)?),
+                None => (parse_page(part)?, parse_page(part)?),
+            };
+            if first > last {
+                return Err(format!("invalid page range `{}` in pages", part.trim()));
+            }
+            Ok(first - 1..=last - 1)
+        })
+        .collect()
+}
+
+// a side of the thumbnail bounding box in pixels, DEFAULT_THUMBNAIL_SIZE when missing
+fn parse_thumbnail_size(params: &HashMap<String, String>, name: &str) -> Result<u32, ApiError> {
+    match params.get(name) {
+        Some(value) => value
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|size| (1..=MAX_THUMBNAIL_SIZE).contains(size))
+            .ok_or_else(|| {
+                ApiError::InvalidParameter(format!(
+                    "{} must be a number of pixels between 1 and {}, got `{}`",
+                    name, MAX_THUMBNAIL_SIZE, value
+                ))
+            }),
+        None => Ok(DEFAULT_THUMBNAIL_SIZE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::GeneratedRect;
+    use axum::{response::Response, routing::get};
+    use tower::ServiceExt;
+
+    // the first bytes of a jpeg, the kind of upload the check is meant to turn away
+    const BOGUS_UPLOAD: &[u8] =
+        b"\xFF\xD8\xFF\xE0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00";
+
+    // the router as main builds it, before the pdfium library is bound
+    fn test_router() -> Router {
+        router(AppState {
+            pdfium: Arc::new(OnceLock::new()),
+            render_cache: None,
+        })
+    }
+
+    async fn response_body(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    // the text layer of a dense page: 60 lines of about 12 words, one text element per word
+    fn text_layer_svg() -> String {
+        let words = [
+            "the",
+            "quarterly",
+            "report",
+            "shows",
+            "revenue",
+            "growth",
+            "across",
+            "all",
+            "regions",
+            "and",
+            "segments",
+            "2024",
+        ];
+        let rects = (0..60)
+            .flat_map(|line| {
+                words.iter().enumerate().map(move |(index, word)| {
+                    let left = 40.0 + index as f32 * 42.5;
+                    let baseline = 60.0 + line as f32 * 12.0;
+                    GeneratedRect {
+                        lx_pos: (0..word.len()).map(|c| left + c as f32 * 5.5).collect(),
+                        ly_pos: vec![baseline; word.len()],
+                        text: word.to_string(),
+                        font_family: "ABCDEF+Helvetica".to_string(),
+                        font_weight: 400,
+                        is_italic: false,
+                        right: left + word.len() as f32 * 5.5,
+                        font_size: 10.0,
+                        baseline,
+                        rotation: 0,
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        page_svg(612.0, 792.0, &rects, "transparent")
+    }
+
+    // a router answering every path with `body` as `content_type`, behind the compression layer
+    fn compressed_router(content_type: &'static str, body: String) -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(move || async move { ([(header::CONTENT_TYPE, content_type)], body) }),
+            )
+            .layer(compression_layer())
+    }
+
+    async fn encoded_response(router: Router, accept_encoding: &str) -> (Option<String>, usize) {
+        let request = Request::get("/")
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let content_encoding = response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|encoding| encoding.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (content_encoding, body.len())
+    }
+
+    #[tokio::test]
+    async fn compression_shrinks_a_text_layer_by_more_than_80_percent() {
+        let svg = text_layer_svg();
+        for (accept_encoding, encoding) in [("gzip", "gzip"), ("gzip, br", "br")] {
+            let router = compressed_router("image/svg+xml", svg.clone());
+            let (content_encoding, size) = encoded_response(router, accept_encoding).await;
+            assert_eq!(content_encoding.as_deref(), Some(encoding));
+            assert!(
+                size * 5 < svg.len(),
+                "{} shrank the {} bytes svg to {} bytes",
+                encoding,
+                svg.len(),
+                size
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn compression_leaves_streams_and_unencoded_clients_alone() {
+        let svg = text_layer_svg();
+        for content_type in ["application/x-ndjson", "application/zip", "application/pdf"] {
+            let router = compressed_router(content_type, svg.clone());
+            assert_eq!(
+                encoded_response(router, "gzip, br").await,
+                (None, svg.len()),
+                "{} was compressed",
+                content_type
+            );
+        }
+        let router = compressed_router("image/svg+xml", svg.clone());
+        assert_eq!(
+            encoded_response(router, "identity").await,
+            (None, svg.len())
+        );
+    }
+
+    #[tokio::test]
+    async fn openapi_spec_documents_every_endpoint() {
+        let request = Request::get("/openapi.json").body(Body::empty()).unwrap();
+        let response = test_router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let spec: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+
+        let paths = spec["paths"].as_object().unwrap();
+        for path in [
+            "/process",
+            "/page/{page}",
+            "/svg",
+            "/merge",
+            "/health",
+            "/ready",
+        ] {
+            assert!(paths.contains_key(path), "{} is missing", path);
+        }
+        // every schema the paths refer to has to be part of the spec
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        let spec = spec.to_string();
+        for reference in spec.split("\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(schemas.contains_key(name), "{} is not in the spec", name);
+        }
+    }
+
+    async fn response_request_id(request_id: Option<&str>) -> String {
+        let mut request = Request::get("/ready");
+        if let Some(request_id) = request_id {
+            request = request.header(REQUEST_ID_HEADER, request_id);
+        }
+        let response = test_router()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn request_id_is_a_uuid_unless_the_client_sent_one() {
+        let generated = response_request_id(None).await;
+        assert!(Uuid::parse_str(&generated).is_ok());
+        assert_eq!(response_request_id(Some("proxy-42")).await, "proxy-42");
+
+        let too_long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for invalid in ["", "has spaces", &too_long] {
+            let replaced = response_request_id(Some(invalid)).await;
+            assert!(Uuid::parse_str(&replaced).is_ok(), "{:?} was kept", invalid);
+        }
+    }
+
+    // a multipart form of (field name, file name, content) parts posted to `uri`
+    fn multipart_request(uri: &str, parts: &[(&str, Option<&str>, &[u8])]) -> Request {
+        let boundary = "test-boundary";
+        let mut body = Vec::new();
+        for (name, file_name, content) in parts {
+            body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+            let disposition = match file_name {
+                Some(file_name) => format!("name=\"{}\"; filename=\"{}\"", name, file_name),
+                None => format!("name=\"{}\"", name),
+            };
+            body.extend_from_slice(
+                format!("Content-Disposition: form-data; {}\r\n\r\n", disposition).as_bytes(),
+            );
+            body.extend_from_slice(content);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        Request::post(uri)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    // the status and error message of an upload turned away before pdfium is involved, which the
+    // test router doesn't have
+    async fn rejected_upload(parts: &[(&str, Option<&str>, &[u8])]) -> (StatusCode, String) {
+        let response = test_router()
+            .oneshot(multipart_request("/page_count", parts))
+            .await
+            .unwrap();
+        let status = response.status();
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        (status, body["error"].as_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn read_pdf_upload_rejects_an_empty_form() {
+        let (status, error) = rejected_upload(&[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error, "no PDF file provided");
+    }
+
+    #[tokio::test]
+    async fn read_pdf_upload_rejects_a_form_without_files() {
+        let (status, error) =
+            rejected_upload(&[("password", None, b"secret"), ("comment", None, b"hello")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error, "no PDF file provided");
+    }
+
+    #[tokio::test]
+    async fn read_pdf_upload_rejects_empty_and_bogus_files() {
+        let (status, error) = rejected_upload(&[("file", Some("empty.pdf"), b"")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error, "the uploaded PDF file is empty");
+
+        let (status, error) = rejected_upload(&[("file", Some("photo.pdf"), BOGUS_UPLOAD)]).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(error, "the uploaded file is not a PDF");
+    }
+
+    #[tokio::test]
+    async fn read_pdf_upload_only_fetches_https_urls() {
+        let response = test_router()
+            .oneshot(multipart_request(
+                "/page_count?url=http://example.com/report.pdf",
+                &[],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let (status, error) = rejected_upload(&[("url", None, b"file:///etc/passwd")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            error,
+            "only https urls can be fetched, got `file:///etc/passwd`"
+        );
+    }
+
+    #[tokio::test]
+    async fn read_pdf_upload_rejects_a_file_past_max_size_mb() {
+        let file = vec![b'a'; 2 * 1024 * 1024];
+        let response = test_router()
+            .oneshot(multipart_request(
+                "/page_count?max_size_mb=1",
+                &[("file", Some("large.pdf"), &file)],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn merge_stops_reading_at_the_51st_file() {
+        let file_names = (1..=MAX_MERGE_FILES + 1)
+            .map(|index| format!("{}.pdf", index))
+            .collect::<Vec<_>>();
+        let parts = file_names
+            .iter()
+            .map(|file_name| ("file", Some(file_name.as_str()), HEALTH_CHECK_PDF))
+            .collect::<Vec<_>>();
+        let response = test_router()
+            .oneshot(multipart_request("/merge", &parts))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body["error"], "at most 50 PDFs can be sent at once");
+
+        // 50 are read, merging them then waits on the library the test router doesn't bind
+        let response = test_router()
+            .oneshot(multipart_request("/merge", &parts[..MAX_MERGE_FILES]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn health_check_pdf_is_a_tiny_single_page_pdf() {
+        assert!(HEALTH_CHECK_PDF.len() < 500);
+        assert!(is_valid_pdf(HEALTH_CHECK_PDF));
+        let Some(pdfium) = crate::test_pdfium() else {
+            return;
+        };
+        let pdfium = lock_pdfium(&pdfium).unwrap();
+        let document = load_document(&pdfium, HEALTH_CHECK_PDF.to_vec(), None).unwrap();
+        assert_eq!(document.pages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn read_pdf_upload_refuses_urls_to_internal_addresses() {
+        for url in ["https://127.0.0.1/x.pdf", "https://[::1]/x.pdf"] {
+            let (status, error) = rejected_upload(&[("url", None, url.as_bytes())]).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(error, format!("{} points to an internal address", url));
+        }
+    }
+
+    #[test]
+    fn is_valid_pdf_accepts_a_pdf() {
+        assert!(is_valid_pdf(HEALTH_CHECK_PDF));
+    }
+
+    #[test]
+    fn is_valid_pdf_accepts_junk_around_the_markers() {
+        let mut data = b"junk before the header\n".to_vec();
+        data.extend_from_slice(HEALTH_CHECK_PDF);
+        data.extend_from_slice(b"\ntrailing garbage");
+        assert!(is_valid_pdf(&data));
+    }
+
+    #[test]
+    fn is_valid_pdf_rejects_a_bogus_payload_with_415() {
+        assert!(!is_valid_pdf(BOGUS_UPLOAD));
+        assert!(!is_valid_pdf(b""));
+        assert_eq!(
+            ApiError::NotAPdf.status_code(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
+    #[test]
+    fn is_valid_pdf_rejects_a_truncated_pdf() {
+        let truncated = &HEALTH_CHECK_PDF[..HEALTH_CHECK_PDF.len() - 10];
+        assert!(!is_valid_pdf(truncated));
+    }
+
+    #[test]
+    fn parse_page_list_reads_pages_and_ranges_numbered_from_1() {
+        assert_eq!(
+            parse_page_list("1-3,5, 10-12"),
+            Ok(vec![0..=2, 4..=4, 9..=11])
+        );
+        assert_eq!(parse_page_list("7"), Ok(vec![6..=6]));
+    }
+
+    #[test]
+    fn parse_page_list_rejects_invalid_pages() {
+        for value in ["0", "3-1", "a", "1-", "-2", "", "1,,2"] {
+            assert!(parse_page_list(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn resolve_page_range_defaults_to_every_page() {
+        assert_eq!(
+            resolve_page_range(&PageRange::default(), 3).unwrap(),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn resolve_page_range_of_an_empty_document_is_empty() {
+        assert_eq!(
+            resolve_page_range(&PageRange::default(), 0).unwrap(),
+            Vec::<usize>::new()
+        );
+        // explicit bounds still have to exist
+        let page_range = PageRange {
+            start: Some(0),
+            ..PageRange::default()
+        };
+        assert!(resolve_page_range(&page_range, 0).is_err());
+    }
+
+    #[test]
+    fn a_document_without_pages_processes_into_an_empty_payload() {
+        assert!(is_valid_pdf(EMPTY_DOCUMENT_PDF));
+        let Some(pdfium) = crate::test_pdfium() else {
+            return;
+        };
+        let pdfium = lock_pdfium(&pdfium).unwrap();
+        let document = load_document(&pdfium, EMPTY_DOCUMENT_PDF.to_vec(), None).unwrap();
+        let page_count = document.pages().len() as usize;
+        let page_indices = resolve_page_range(&PageRange::default(), page_count).unwrap();
+        let render_options = RenderOptions::from_params(&HashMap::new()).unwrap();
+
+        let mut pages = Vec::new();
+        process_document(&document, page_indices, &render_options, None, |page| {
+            pages.push(page);
+            Ok(())
+        })
+        .unwrap();
+        let response = DocumentPayloadResponse::from(DocumentPayload { page_count, pages });
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            serde_json::json!({ "page_count": 0, "pages": [] })
+        );
+    }
+
+    #[test]
+    fn resolve_page_range_applies_the_bounds() {
+        let page_range = PageRange {
+            start: Some(1),
+            end: Some(2),
+            ..PageRange::default()
+        };
+        assert_eq!(resolve_page_range(&page_range, 5).unwrap(), vec![1, 2]);
+
+        let past_the_end = PageRange {
+            end: Some(5),
+            ..PageRange::default()
+        };
+        assert!(resolve_page_range(&past_the_end, 5).is_err());
+
+        let reversed = PageRange {
+            start: Some(3),
+            end: Some(1),
+            ..PageRange::default()
+        };
+        assert!(resolve_page_range(&reversed, 5).is_err());
+    }
+
+    #[test]
+    fn resolve_page_range_sorts_and_dedups_the_list() {
+        let page_range = PageRange {
+            list: parse_page_list("4-5,1,2-4,9-20").ok(),
+            ..PageRange::default()
+        };
+        // pages past the end are skipped unless strict
+        assert_eq!(
+            resolve_page_range(&page_range, 10).unwrap(),
+            vec![0, 1, 2, 3, 4, 8, 9]
+        );
+
+        let strict = PageRange {
+            strict: true,
+            ..page_range
+        };
+        assert!(resolve_page_range(&strict, 10).is_err());
+    }
+
+    fn search_match(
+        page: usize,
+        text: &str,
+        rects: &[(f32, f32, f32, f32)],
+    ) -> SearchMatchResponse {
+        SearchMatchResponse {
+            page,
+            text: text.to_string(),
+            rects: rects
+                .iter()
+                .map(|&(x, y, width, height)| RectResponse {
+                    x,
+                    y,
+                    width,
+                    height,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn group_matches_by_page_boxes_every_hit_of_a_page() {
+        let matches = vec![
+            search_match(0, "photo", &[(10.0, 20.0, 30.0, 12.0)]),
+            // a hit spanning two lines
+            search_match(
+                0,
+                "photosynthesis",
+                &[(150.0, 20.0, 40.0, 12.0), (10.0, 34.0, 50.0, 12.0)],
+            ),
+            search_match(3, "Photo", &[(5.0, 5.0, 25.0, 10.0)]),
+        ];
+        let pages = serde_json::to_value(group_matches_by_page(matches)).unwrap();
+        assert_eq!(
+            pages,
+            serde_json::json!([
+                {
+                    "page": 0,
+                    "matches": [
+                        { "text": "photo", "x": 10.0, "y": 20.0, "width": 30.0, "height": 12.0 },
+                        { "text": "photosynthesis", "x": 10.0, "y": 20.0, "width": 180.0, "height": 26.0 },
+                    ]
+                },
+                {
+                    "page": 3,
+                    "matches": [
+                        { "text": "Photo", "x": 5.0, "y": 5.0, "width": 25.0, "height": 10.0 },
+                    ]
+                },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn search_rejects_an_unknown_group() {
+        let response = test_router()
+            .oneshot(multipart_request("/search?q=photo&group=line", &[]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body["error"], "unsupported group `line`, expected page");
+    }
+}
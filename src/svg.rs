@@ -0,0 +1,434 @@
+// the svg text layer laid over the page images, along with the helpers to safely write pdf content
+// into its markup
+use crate::text::GeneratedRect;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// escapes the five characters xml gives a special meaning to, text coming out of a pdf can
+// contain any of them and would otherwise produce invalid svg
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// used when the pdf font can't be matched to anything a browser is likely to have
+const SYSTEM_FONT_STACK: &str = "-apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif";
+// well known pdf base fonts and the css families that render closest to them, matched against the
+// lowercased font name so `TimesNewRomanPS-BoldMT` and `Times-Roman` both land on the serif stack
+const FONT_FAMILY_TABLE: [(&str, &str); 12] = [
+    ("helvetica", "Helvetica, Arial, sans-serif"),
+    ("arial", "Arial, Helvetica, sans-serif"),
+    ("verdana", "Verdana, Geneva, sans-serif"),
+    ("calibri", "Calibri, Carlito, sans-serif"),
+    ("times", "'Times New Roman', Times, serif"),
+    ("georgia", "Georgia, serif"),
+    ("garamond", "Garamond, 'EB Garamond', serif"),
+    ("cambria", "Cambria, Caladea, serif"),
+    ("courier", "'Courier New', Courier, monospace"),
+    ("consolas", "Consolas, 'Courier New', monospace"),
+    ("symbol", "Symbol, serif"),
+    ("dingbats", "'Zapf Dingbats', serif"),
+];
+
+// pdf embedded subsets prefix the font name with six uppercase letters and a plus sign,
+// e.g. `ABCDEF+Helvetica`
+fn strip_subset_prefix(font_name: &str) -> &str {
+    match font_name.split_once('+') {
+        Some((prefix, name))
+            if prefix.len() == 6 && prefix.chars().all(|c| c.is_ascii_uppercase()) =>
+        {
+            name
+        }
+        _ => font_name,
+    }
+}
+
+// maps a pdf font name to a css font-family list, unknown fonts are tried by name first and then
+// fall back to the system stack
+fn font_family_to_css(font_name: &str) -> String {
+    let name = strip_subset_prefix(font_name);
+    // style suffixes like `-Bold` or `,Italic` aren't part of the family
+    let family = name.split(['-', ',']).next().unwrap_or(name);
+    let lowercase_family = family.to_ascii_lowercase();
+
+    if let Some((_, css)) = FONT_FAMILY_TABLE
+        .iter()
+        .find(|(pdf_name, _)| lowercase_family.contains(pdf_name))
+    {
+        return css.to_string();
+    }
+
+    // the family ends up quoted inside a style attribute, keep only characters that are safe there
+    let family: String = family
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == ' ' || *c == '_')
+        .collect();
+    if family.is_empty() {
+        return SYSTEM_FONT_STACK.to_string();
+    }
+    format!("'{}', {}", family, SYSTEM_FONT_STACK)
+}
+
+// class of the group of a font, `font-TimesNewRoman` for `ABCDEF+TimesNewRoman-Bold`, so a stylesheet
+// can substitute the font. Only the characters allowed in a class name are kept, a font without any
+// (unnamed, or named in another script) gets `font-unknown` rather than a bare `font-`
+fn font_class(font_name: &str) -> String {
+    let name = strip_subset_prefix(font_name);
+    let family = name.split(['-', ',']).next().unwrap_or(name);
+    let family: String = family
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect();
+    if family.is_empty() {
+        return "font-unknown".to_string();
+    }
+    format!("font-{}", family)
+}
+
+// css font-weight implied by the font name, checked in order so `SemiBold` isn't read as `Bold`
+// and `ExtraLight` isn't read as `Light`
+const FONT_WEIGHT_TABLE: [(&str, u16); 13] = [
+    ("thin", 100),
+    ("hairline", 100),
+    ("extralight", 200),
+    ("ultralight", 200),
+    ("light", 300),
+    ("medium", 500),
+    ("semibold", 600),
+    ("demibold", 600),
+    ("extrabold", 800),
+    ("ultrabold", 800),
+    ("bold", 700),
+    ("black", 900),
+    ("heavy", 900),
+];
+
+// pdf fonts usually encode their weight and style in the name,
+// e.g. `Arial-BoldItalic` or `TimesNewRomanPS-BoldMT`. Returns the css font-weight and whether
+// the font is italic
+pub fn font_style_from_name(font_name: &str) -> (u16, bool) {
+    let name = strip_subset_prefix(font_name).to_ascii_lowercase();
+    let font_weight = FONT_WEIGHT_TABLE
+        .iter()
+        .find(|(style, _)| name.contains(style))
+        .map_or(400, |(_, weight)| *weight);
+    let is_italic = name.contains("italic") || name.contains("oblique");
+    (font_weight, is_italic)
+}
+
+// the x and y attributes of the tspan of a rect. A list of values places each char of the text on its
+// own, which only holds when there is exactly one position per char: a pdf char mapping to several
+// unicode chars would shift every following glyph. Those rects fall back to the position of their
+// first char and let the browser lay out the rest
+fn tspan_positions(rect: &GeneratedRect) -> (String, String) {
+    let char_count = rect.text.chars().count();
+    if rect.lx_pos.len() == char_count && rect.ly_pos.len() == char_count {
+        let join = |positions: &[f32]| {
+            positions
+                .iter()
+                .map(|num| num.to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        return (join(&rect.lx_pos), join(&rect.ly_pos));
+    }
+    tracing::debug!(
+        chars = char_count,
+        x_positions = rect.lx_pos.len(),
+        y_positions = rect.ly_pos.len(),
+        "the positions don't match the chars of the text, only the first one is kept"
+    );
+    let first = |positions: &[f32]| positions.first().map_or(0.0, |num| *num).to_string();
+    (first(&rect.lx_pos), first(&rect.ly_pos))
+}
+
+// returns the svg string from the generated text rects, an empty string when there are none. Only
+// depends on its arguments, pdfium is not involved once the rects are extracted
+pub fn get_string_from_rects(
+    page_width: f32,
+    page_height: f32,
+    rects: &[GeneratedRect],
+    text_fill: &str,
+) -> String {
+    if rects.is_empty() {
+        return String::new();
+    }
+    page_svg(page_width, page_height, rects, text_fill)
+}
+
+// same as get_string_from_rects but always a full svg document, a page without text gives an empty one
+pub fn page_svg(
+    page_width: f32,
+    page_height: f32,
+    rects: &[GeneratedRect],
+    text_fill: &str,
+) -> String {
+    let mut svg_content = format!(
+        r#"<svg 
+        xmlns="http://www.w3.org/2000/svg" 
+        width="{page_width}" 
+        height="{page_height}" 
+        viewBox="0 0 {page_width} {page_height}" 
+        data-page-width="{page_width}" 
+        data-page-height="{page_height}" 
+        style="font-family: {system_font_stack}; text-rendering: optimizeLegibility; shape-rendering: geometricPrecision"><title>text-layer</title>"#,
+        page_width = page_width,
+        page_height = page_height,
+        system_font_stack = SYSTEM_FONT_STACK,
+    );
+
+    // one group per pdf font, sorted by name so the same page always gives the same svg. The font
+    // family is set once on the group, a stylesheet can swap it through the class or data-font-family
+    let mut font_groups: BTreeMap<&str, Vec<&GeneratedRect>> = BTreeMap::new();
+    for rect in rects {
+        font_groups
+            .entry(rect.font_family.as_str())
+            .or_default()
+            .push(rect);
+    }
+
+    for (font_name, rects) in font_groups {
+        let _ = write!(
+            svg_content,
+            r#"<g class="{class}" data-font-family="{font_name}" style="font-family: {font_family}">"#,
+            class = font_class(font_name),
+            font_name = escape_xml(font_name),
+            font_family = font_family_to_css(font_name),
+        );
+        for rect in rects {
+            write_text(&mut svg_content, rect, text_fill);
+        }
+        svg_content.push_str("</g>");
+    }
+
+    svg_content.push_str("</svg>");
+    svg_content
+}
+
+// writes the text element of a rect, the font family comes from its group
+fn write_text(svg_content: &mut String, rect: &GeneratedRect, text_fill: &str) {
+    // Add text element with orientation-aware styling, the pdf font name is kept as is in data-font
+    // so viewers can load the matching font themselves
+    let _ = write!(
+        svg_content,
+        r#"<text 
+            data-font="{font_name}" 
+            style="font-size:{font_size}pt; white-space: pre; text-rendering: geometricPrecision; dominant-baseline: hanging; font-weight: {font_weight}; font-style: {font_style}; letter-spacing: -0.01em; fill: {text_fill};">"#,
+        font_name = escape_xml(&rect.font_family),
+        font_size = rect.font_size,
+        font_weight = rect.font_weight,
+        font_style = if rect.is_italic { "italic" } else { "normal" },
+        text_fill = text_fill,
+    );
+
+    let (primary_value, secondary_value) = tspan_positions(rect);
+    let _ = write!(
+        svg_content,
+        r#"<tspan x="{primary_value}" y="{secondary_value}"{rotate}>{text}</tspan></text>"#,
+        primary_value = primary_value,
+        secondary_value = secondary_value,
+        // a single value applies to every glyph
+        rotate = if rect.rotation == 0 {
+            String::new()
+        } else {
+            format!(r#" rotate="{}""#, rect.rotation)
+        },
+        text = escape_xml(&rect.text)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_xml_escapes_each_special_character() {
+        assert_eq!(escape_xml("&"), "&amp;");
+        assert_eq!(escape_xml("<"), "&lt;");
+        assert_eq!(escape_xml(">"), "&gt;");
+        assert_eq!(escape_xml("\""), "&quot;");
+        assert_eq!(escape_xml("'"), "&apos;");
+    }
+
+    #[test]
+    fn escape_xml_escapes_all_special_characters_together() {
+        assert_eq!(
+            escape_xml(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn font_style_from_name_reads_common_naming_conventions() {
+        assert_eq!(font_style_from_name("Helvetica"), (400, false));
+        assert_eq!(font_style_from_name("Arial-BoldItalic"), (700, true));
+        assert_eq!(font_style_from_name("TimesNewRomanPS-BoldMT"), (700, false));
+        assert_eq!(
+            font_style_from_name("TimesNewRomanPS-ItalicMT"),
+            (400, true)
+        );
+        assert_eq!(font_style_from_name("Helvetica-Oblique"), (400, true));
+        assert_eq!(font_style_from_name("Arial,Bold"), (700, false));
+        assert_eq!(font_style_from_name("Roboto-Light"), (300, false));
+        assert_eq!(font_style_from_name("Lato-Black"), (900, false));
+        assert_eq!(font_style_from_name("Montserrat-Medium"), (500, false));
+    }
+
+    #[test]
+    fn font_style_from_name_doesnt_mistake_compound_weights() {
+        assert_eq!(font_style_from_name("OpenSans-SemiBold"), (600, false));
+        assert_eq!(
+            font_style_from_name("SourceSansPro-ExtraLight"),
+            (200, false)
+        );
+        assert_eq!(font_style_from_name("Inter-ExtraBoldItalic"), (800, true));
+    }
+
+    #[test]
+    fn font_style_from_name_ignores_the_subset_prefix() {
+        // `BOLDAB+` is a subset tag, not a weight
+        assert_eq!(font_style_from_name("BOLDAB+Helvetica"), (400, false));
+        assert_eq!(font_style_from_name("ABCDEF+Arial-BoldMT"), (700, false));
+    }
+
+    #[test]
+    fn escape_xml_keeps_other_text() {
+        assert_eq!(escape_xml("plain text, déjà vu"), "plain text, déjà vu");
+        assert_eq!(escape_xml(""), "");
+    }
+
+    #[test]
+    fn font_family_to_css_maps_base_fonts_to_their_stacks() {
+        assert_eq!(
+            font_family_to_css("ABCDEF+Helvetica-Bold"),
+            "Helvetica, Arial, sans-serif"
+        );
+        assert_eq!(
+            font_family_to_css("TimesNewRomanPS-BoldMT"),
+            "'Times New Roman', Times, serif"
+        );
+        assert_eq!(
+            font_family_to_css("CourierNewPSMT"),
+            "'Courier New', Courier, monospace"
+        );
+    }
+
+    #[test]
+    fn font_family_to_css_tries_unknown_fonts_by_name() {
+        assert_eq!(
+            font_family_to_css("Lato-Regular"),
+            format!("'Lato', {}", SYSTEM_FONT_STACK)
+        );
+        // quotes and semicolons would break out of the style attribute
+        assert_eq!(
+            font_family_to_css("Evil';}<x>"),
+            format!("'Evilx', {}", SYSTEM_FONT_STACK)
+        );
+        assert_eq!(font_family_to_css(""), SYSTEM_FONT_STACK);
+    }
+
+    fn rect(text: &str, lx_pos: Vec<f32>, ly_pos: Vec<f32>) -> GeneratedRect {
+        GeneratedRect {
+            lx_pos,
+            ly_pos,
+            text: text.to_string(),
+            font_family: "Helvetica".to_string(),
+            font_weight: 400,
+            is_italic: false,
+            right: 0.0,
+            font_size: 12.0,
+            baseline: 0.0,
+            rotation: 0,
+        }
+    }
+
+    #[test]
+    fn font_class_keeps_the_family_name() {
+        assert_eq!(
+            font_class("ABCDEF+TimesNewRoman-Bold"),
+            "font-TimesNewRoman"
+        );
+        assert_eq!(font_class("Arial,Italic"), "font-Arial");
+        assert_eq!(font_class("Noto Sans_CJK"), "font-NotoSans_CJK");
+    }
+
+    #[test]
+    fn font_class_falls_back_to_unknown_without_a_usable_name() {
+        assert_eq!(font_class(""), "font-unknown");
+        assert_eq!(font_class("ABCDEF+"), "font-unknown");
+        assert_eq!(font_class("-Bold"), "font-unknown");
+        assert_eq!(font_class("宋体"), "font-unknown");
+    }
+
+    #[test]
+    fn tspan_positions_lists_one_position_per_char() {
+        // four chars but six bytes
+        let accented = rect("déjà", vec![1.0, 2.5, 4.0, 5.5], vec![10.0; 4]);
+        assert_eq!(
+            tspan_positions(&accented),
+            ("1 2.5 4 5.5".to_string(), "10 10 10 10".to_string())
+        );
+    }
+
+    #[test]
+    fn tspan_positions_falls_back_to_the_first_char_when_they_dont_match() {
+        // a ligature, one position for two chars
+        let ligature = rect("fi", vec![3.0], vec![7.0]);
+        assert_eq!(
+            tspan_positions(&ligature),
+            ("3".to_string(), "7".to_string())
+        );
+
+        let mismatched_y = rect("ab", vec![1.0, 2.0], vec![7.0]);
+        assert_eq!(
+            tspan_positions(&mismatched_y),
+            ("1".to_string(), "7".to_string())
+        );
+    }
+
+    #[test]
+    fn get_string_from_rects_is_empty_without_rects() {
+        assert_eq!(get_string_from_rects(100.0, 50.0, &[], "transparent"), "");
+    }
+
+    #[test]
+    fn page_svg_without_rects_is_an_empty_document() {
+        insta::assert_snapshot!(page_svg(100.0, 50.0, &[], "transparent"));
+    }
+
+    #[test]
+    fn get_string_from_rects_writes_a_position_per_char() {
+        let mut bold_italic = rect("Hi", vec![10.0, 16.5], vec![20.0, 20.0]);
+        bold_italic.font_family = "ABCDEF+Arial-BoldItalic".to_string();
+        bold_italic.font_weight = 700;
+        bold_italic.is_italic = true;
+        let rotated = GeneratedRect {
+            rotation: 90,
+            ..rect("ok", vec![30.0, 30.0], vec![5.0, 11.0])
+        };
+        insta::assert_snapshot!(get_string_from_rects(
+            100.0,
+            50.0,
+            &[bold_italic, rotated],
+            "transparent"
+        ));
+    }
+
+    #[test]
+    fn get_string_from_rects_escapes_special_characters() {
+        let special = rect(r#"<a & "b" 'c'>"#, vec![1.0], vec![2.0]);
+        let mut font = rect("x", vec![3.0], vec![4.0]);
+        font.font_family = "Odd&<Font>".to_string();
+        insta::assert_snapshot!(get_string_from_rects(100.0, 50.0, &[special, font], "#333"));
+    }
+}
@@ -0,0 +1,655 @@
+// reading the text off a page: the char groups behind the svg layer, the words, and the mapping from
+// pdf coordinates to the top left based ones of the displayed page
+use crate::error::ApiError;
+use crate::svg::font_style_from_name;
+use pdfium_render::prelude::*;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+// a gap between two chars of a group wider than this fraction of the font size is a space
+const SPACE_GAP_RATIO: f32 = 0.2;
+// a char whose baseline moved by more than this fraction of the font size starts a new group
+const BASELINE_SHIFT_RATIO: f32 = 0.3;
+
+// what a group of the svg text stands for
+#[derive(Clone, Copy, PartialEq, Eq, ToSchema)]
+#[schema(rename_all = "lowercase")]
+pub enum Granularity {
+    // every char on its own
+    Char,
+    // runs of chars without whitespace or a wide gap between them
+    Word,
+    // runs of chars sharing a baseline and a font, the default
+    Run,
+    // everything sharing a baseline, whatever the font. The group takes the font of its first char
+    Line,
+}
+
+impl Granularity {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "char" => Ok(Granularity::Char),
+            "word" => Ok(Granularity::Word),
+            "run" => Ok(Granularity::Run),
+            "line" => Ok(Granularity::Line),
+            _ => Err(format!(
+                "unsupported granularity `{}`, expected one of char, word, run or line",
+                value
+            )),
+        }
+    }
+}
+
+// how the chars of a page are split into the groups of the svg text, tunable per request
+#[derive(Clone, Copy)]
+pub struct TextGrouping {
+    pub granularity: Granularity,
+    // gap in points past which two chars are split by a space, a fraction of the font size when missing
+    pub gap_threshold: Option<f32>,
+    // fraction of the font size the baseline can move by before a char starts a new group, i.e. a new line
+    pub y_band_tolerance: f32,
+}
+
+impl Default for TextGrouping {
+    fn default() -> Self {
+        TextGrouping {
+            granularity: Granularity::Run,
+            gap_threshold: None,
+            y_band_tolerance: BASELINE_SHIFT_RATIO,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GeneratedRect {
+    pub lx_pos: Vec<f32>,
+    pub ly_pos: Vec<f32>,
+    pub text: String,
+    pub font_family: String,
+    // derived from the font name once per group so the svg writer doesn't have to re-parse it
+    pub font_weight: u16,
+    pub is_italic: bool,
+    pub right: f32,
+    pub font_size: f32,
+    // origin of the first char, the group ends as soon as a char sits on another line
+    pub baseline: f32,
+    // clockwise rotation of the page in degrees, the glyphs are turned by as much to follow the text
+    pub rotation: u16,
+}
+
+impl GeneratedRect {
+    // word spaces are around a quarter of an em while letter spacing rarely goes past a tenth of it,
+    // unless the client gave a threshold in points
+    fn is_word_gap(&self, gap: f32, grouping: &TextGrouping) -> bool {
+        gap > grouping
+            .gap_threshold
+            .unwrap_or(self.font_size * SPACE_GAP_RATIO)
+    }
+
+    fn is_baseline_shift(&self, char_origin_y: f32, grouping: &TextGrouping) -> bool {
+        (char_origin_y - self.baseline).abs() > self.font_size * grouping.y_band_tolerance
+    }
+
+    // appends a char given its origin in the unrotated page, `height` is the one of its loose bounds.
+    // The text hangs from its position, so that's the top of the glyph on the displayed page
+    fn push(
+        &mut self,
+        text: &str,
+        origin_x: f32,
+        origin_y: f32,
+        height: f32,
+        transform: &PageTransform,
+    ) {
+        self.font_size = self.font_size.max(height);
+        let (x, y) = transform.to_display(origin_x, origin_y + self.font_size);
+        self.lx_pos.push(x);
+        self.ly_pos.push(y);
+        self.text.push_str(text);
+    }
+}
+
+// maps pdf page space (origin at the bottom left, before the /Rotate of the page is applied) to the
+// page as it is displayed and rendered, with the origin at the top left. pdfium already reports the
+// page size and renders the bitmaps with the rotation applied, only the text positions need this
+pub struct PageTransform {
+    pub rotation: PdfPageRenderRotation,
+    // size of the unrotated page
+    pub width: f32,
+    pub height: f32,
+}
+
+impl PageTransform {
+    pub fn new(page: &PdfPage<'_>) -> Result<Self, ApiError> {
+        let rotation = page.rotation()?;
+        let (width, height) = match rotation {
+            PdfPageRenderRotation::Degrees90 | PdfPageRenderRotation::Degrees270 => {
+                (page.height().value, page.width().value)
+            }
+            PdfPageRenderRotation::None | PdfPageRenderRotation::Degrees180 => {
+                (page.width().value, page.height().value)
+            }
+        };
+        Ok(PageTransform {
+            rotation,
+            width,
+            height,
+        })
+    }
+
+    fn degrees(&self) -> u16 {
+        rotation_degrees(self.rotation)
+    }
+
+    // the rotation is clockwise
+    fn to_display(&self, x: f32, y: f32) -> (f32, f32) {
+        match self.rotation {
+            PdfPageRenderRotation::None => (x, self.height - y),
+            PdfPageRenderRotation::Degrees90 => (y, x),
+            PdfPageRenderRotation::Degrees180 => (self.width - x, y),
+            PdfPageRenderRotation::Degrees270 => (self.height - y, self.width - x),
+        }
+    }
+
+    // returns the x, y, width and height of the displayed rect
+    pub fn rect_to_display(&self, rect: &PdfRect) -> (f32, f32, f32, f32) {
+        let (x1, y1) = self.to_display(rect.left().value, rect.bottom().value);
+        let (x2, y2) = self.to_display(rect.right().value, rect.top().value);
+        (x1.min(x2), y1.min(y2), (x2 - x1).abs(), (y2 - y1).abs())
+    }
+}
+
+// a box on the displayed page, in points from its top left corner
+#[derive(Clone, Copy, Serialize, ToSchema)]
+pub struct RectResponse {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+// a single word of a page and its bounding box, in points from the top left corner of the page
+#[derive(Serialize, ToSchema)]
+pub struct WordRect {
+    pub text: String,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub font_size: f32,
+}
+
+impl WordRect {
+    // appends a char to the word and grows the box so it covers the char box as well
+    fn push(&mut self, text: &str, x: f32, y: f32, width: f32, height: f32, font_size: f32) {
+        let left = self.x.min(x);
+        let top = self.y.min(y);
+        let right = (self.x + self.width).max(x + width);
+        let bottom = (self.y + self.height).max(y + height);
+
+        self.text.push_str(text);
+        self.x = left;
+        self.y = top;
+        self.width = right - left;
+        self.height = bottom - top;
+        self.font_size = self.font_size.max(font_size);
+    }
+}
+
+pub fn rotation_degrees(rotation: PdfPageRenderRotation) -> u16 {
+    match rotation {
+        PdfPageRenderRotation::None => 0,
+        PdfPageRenderRotation::Degrees90 => 90,
+        PdfPageRenderRotation::Degrees180 => 180,
+        PdfPageRenderRotation::Degrees270 => 270,
+    }
+}
+
+// control characters (except tabs) and line breaks have no place in the svg text,
+// lone utf-16 surrogates never get here since they don't map to a char
+fn is_unprintable(c: char) -> bool {
+    c == '\r' || c == '\n' || (c.is_control() && c != '\t')
+}
+
+// what the grouping needs to know about a char of the page, in pdf page space
+#[derive(Clone)]
+struct PageChar {
+    text: String,
+    font_name: String,
+    origin_x: f32,
+    origin_y: f32,
+    // horizontal extent and height of the loose bounds
+    left: f32,
+    right: f32,
+    height: f32,
+}
+
+// returns the text boxes for this page
+pub fn extract_page_text_groups(
+    page: &PdfPage<'_>,
+    transform: &PageTransform,
+    grouping: &TextGrouping,
+) -> Result<Vec<GeneratedRect>, ApiError> {
+    let _span = tracing::info_span!("extract_page_text_groups").entered();
+    let text = page.text()?;
+    let chars: PdfPageTextChars = text.chars();
+
+    let mut page_chars = Vec::new();
+    for char in chars.iter() {
+        // chars without a unicode mapping can't be represented in the text layer
+        let Some(text) = char.unicode_string() else {
+            continue;
+        };
+        let loose_bounds = char.loose_bounds()?;
+        page_chars.push(PageChar {
+            text,
+            font_name: char.font_name(),
+            origin_x: char.origin_x()?.value,
+            origin_y: char.origin_y()?.value,
+            left: loose_bounds.left().value,
+            right: loose_bounds.right().value,
+            height: loose_bounds.height().value,
+        });
+    }
+    Ok(group_chars(page_chars, transform, grouping))
+}
+
+// calculated manually by iterating over the chars to get their absolute origin, a group runs for as long
+// as the chars share a baseline (within the y band tolerance) and a font. Gaps wider than a fraction of
+// the font size become spaces, pdfs often leave the space glyphs out and rely on the positions alone.
+// `gap_threshold` replaces that fraction with a fixed number of points, e.g. for condensed or widely
+// spaced fonts
+fn group_chars(
+    chars: Vec<PageChar>,
+    transform: &PageTransform,
+    grouping: &TextGrouping,
+) -> Vec<GeneratedRect> {
+    let mut groups: Vec<GeneratedRect> = Vec::new();
+    let mut current_group: Option<GeneratedRect> = None;
+
+    for char in chars {
+        let PageChar {
+            text: curr,
+            font_name: font_family,
+            origin_x: char_origin_x,
+            origin_y: char_origin_y,
+            left,
+            right,
+            height,
+        } = char;
+
+        // the grouping happens in the unrotated page, where lines of text run along the x axis,
+        // with y flipped due to the different origin. Only the positions written to the svg are rotated
+        let baseline = transform.height - char_origin_y;
+
+        // skip the iteration if the char is outside the page, if the current char is not printable or if its height is 0.0
+        if char_origin_x < 0.0
+            || baseline < 0.0
+            || curr.chars().any(is_unprintable)
+            || height == 0.0
+        {
+            continue;
+        }
+
+        // words don't keep the whitespace between them
+        if grouping.granularity == Granularity::Word && curr.chars().all(char::is_whitespace) {
+            groups.extend(current_group.take());
+            continue;
+        }
+        let continues_group = |group: &GeneratedRect| {
+            let same_line = !group.is_baseline_shift(baseline, grouping);
+            match grouping.granularity {
+                Granularity::Char => false,
+                Granularity::Word => {
+                    same_line
+                        && group.font_family == font_family
+                        && !group.is_word_gap(left - group.right, grouping)
+                }
+                Granularity::Run => same_line && group.font_family == font_family,
+                Granularity::Line => same_line,
+            }
+        };
+
+        match current_group {
+            Some(ref mut group) if continues_group(group) => {
+                let gap = left - group.right;
+                if group.is_word_gap(gap, grouping)
+                    && !group.text.ends_with(char::is_whitespace)
+                    && !curr.starts_with(char::is_whitespace)
+                {
+                    group.push(" ", group.right, char_origin_y, 0.0, transform);
+                }
+                group.push(&curr, char_origin_x, char_origin_y, height, transform);
+                group.right = right;
+            }
+            _ => {
+                groups.extend(current_group.take());
+                let (font_weight, is_italic) = font_style_from_name(&font_family);
+                let (x, y) = transform.to_display(char_origin_x, char_origin_y + height);
+                current_group = Some(GeneratedRect {
+                    lx_pos: vec![x],
+                    ly_pos: vec![y],
+                    text: curr.clone(),
+                    font_family: font_family.clone(),
+                    font_weight,
+                    is_italic,
+                    right,
+                    font_size: height,
+                    baseline,
+                    rotation: transform.degrees(),
+                });
+            }
+        }
+    }
+
+    if let Some(last_group) = current_group {
+        groups.push(last_group);
+    }
+    groups
+}
+
+// splits the text of the page into words, any whitespace or line break in the char stream ends the current
+// word. Like in the text layer, so does a gap wider than a space or a char on another line, since pdfs often
+// leave the space glyphs out
+pub fn extract_page_words(
+    page: &PdfPage<'_>,
+    transform: &PageTransform,
+) -> Result<Vec<WordRect>, ApiError> {
+    let text = page.text()?;
+
+    let mut words: Vec<WordRect> = Vec::new();
+    let mut current_word: Option<WordRect> = None;
+    // right edge and bottom of the previous char of the word, in the unrotated page
+    let mut previous_bounds: Option<(f32, f32)> = None;
+
+    for char in text.chars().iter() {
+        let Some(curr) = char.unicode_string() else {
+            continue;
+        };
+        if curr.chars().all(|c| c.is_whitespace() || is_unprintable(c)) {
+            words.extend(current_word.take());
+            previous_bounds = None;
+            continue;
+        }
+
+        let loose_bounds = char.loose_bounds()?;
+        // same as the text layer, chars without a size can't be placed on the page
+        if loose_bounds.height().value == 0.0 {
+            continue;
+        }
+
+        let (x, y, width, height) = transform.rect_to_display(&loose_bounds);
+        let font_size = loose_bounds.height().value;
+        if let (Some(word), Some((previous_right, previous_bottom))) =
+            (&current_word, previous_bounds)
+        {
+            let gap = loose_bounds.left().value - previous_right;
+            let baseline_shift = (loose_bounds.bottom().value - previous_bottom).abs();
+            if gap > word.font_size * SPACE_GAP_RATIO
+                || baseline_shift > word.font_size * BASELINE_SHIFT_RATIO
+            {
+                words.extend(current_word.take());
+            }
+        }
+        previous_bounds = Some((loose_bounds.right().value, loose_bounds.bottom().value));
+
+        match current_word {
+            Some(ref mut word) => word.push(&curr, x, y, width, height, font_size),
+            None => {
+                current_word = Some(WordRect {
+                    text: curr,
+                    x,
+                    y,
+                    width,
+                    height,
+                    font_size,
+                })
+            }
+        }
+    }
+
+    words.extend(current_word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an unrotated 200 by 200 points page
+    fn page_transform() -> PageTransform {
+        PageTransform {
+            rotation: PdfPageRenderRotation::None,
+            width: 200.0,
+            height: 200.0,
+        }
+    }
+
+    // the chars of `text` on the baseline `y`, every glyph `width` wide and the next one starting
+    // `advance` points further. A space in `text` only moves the position, like a pdf leaving it out
+    fn line_chars(
+        text: &str,
+        font: &str,
+        size: f32,
+        y: f32,
+        width: f32,
+        advance: f32,
+    ) -> Vec<PageChar> {
+        let mut x = 10.0;
+        let mut chars = Vec::new();
+        for c in text.chars() {
+            if c != ' ' {
+                chars.push(PageChar {
+                    text: c.to_string(),
+                    font_name: font.to_string(),
+                    origin_x: x,
+                    origin_y: y,
+                    left: x,
+                    right: x + width,
+                    height: size,
+                });
+            }
+            x += advance;
+        }
+        chars
+    }
+
+    // a 10pt monospace line whose glyphs touch each other, and a 30pt heading with 5pt of letter
+    // spacing, a word gap on the first and a letter gap on the second are both 6pt wide
+    fn mixed_spacing_page() -> Vec<PageChar> {
+        let mut chars = line_chars("TITLE", "Helvetica-Bold", 30.0, 180.0, 18.0, 23.0);
+        chars.extend(line_chars("let x = 1;", "Courier", 10.0, 100.0, 6.0, 6.0));
+        chars
+    }
+
+    fn group_texts(chars: Vec<PageChar>, grouping: TextGrouping) -> Vec<String> {
+        group_chars(chars, &page_transform(), &grouping)
+            .into_iter()
+            .map(|group| group.text)
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn to_display_maps_the_corners_of_every_rotation() {
+        // the corners of an unrotated 200 by 100 points page, in pdf space: bottom left, bottom
+        // right, top left and top right
+        let corners = [(0.0, 0.0), (200.0, 0.0), (0.0, 100.0), (200.0, 100.0)];
+        let cases = [
+            (
+                PdfPageRenderRotation::None,
+                [(0.0, 100.0), (200.0, 100.0), (0.0, 0.0), (200.0, 0.0)],
+                (200.0, 100.0),
+            ),
+            (
+                PdfPageRenderRotation::Degrees90,
+                [(0.0, 0.0), (0.0, 200.0), (100.0, 0.0), (100.0, 200.0)],
+                (100.0, 200.0),
+            ),
+            (
+                PdfPageRenderRotation::Degrees180,
+                [(200.0, 0.0), (0.0, 0.0), (200.0, 100.0), (0.0, 100.0)],
+                (200.0, 100.0),
+            ),
+            (
+                PdfPageRenderRotation::Degrees270,
+                [(100.0, 200.0), (100.0, 0.0), (0.0, 200.0), (0.0, 0.0)],
+                (100.0, 200.0),
+            ),
+        ];
+        for (rotation, expected, (displayed_width, displayed_height)) in cases {
+            let transform = PageTransform {
+                rotation,
+                width: 200.0,
+                height: 100.0,
+            };
+            let displayed = corners.map(|(x, y)| transform.to_display(x, y));
+            assert_eq!(displayed, expected, "{:?}", rotation);
+            // the corners span the displayed page, its width and height swap for 90 and 270
+            let right = displayed.iter().map(|&(x, _)| x).fold(0.0, f32::max);
+            let bottom = displayed.iter().map(|&(_, y)| y).fold(0.0, f32::max);
+            assert_eq!(
+                (right, bottom),
+                (displayed_width, displayed_height),
+                "{:?}",
+                rotation
+            );
+        }
+    }
+
+    #[test]
+    fn group_chars_scales_the_word_gap_with_the_font_size() {
+        let texts = group_texts(mixed_spacing_page(), TextGrouping::default());
+        assert_eq!(texts, ["TITLE", "let x = 1;"]);
+    }
+
+    #[test]
+    fn group_chars_uses_the_gap_threshold_for_every_font_size() {
+        let grouping = TextGrouping {
+            gap_threshold: Some(4.0),
+            ..TextGrouping::default()
+        };
+        let texts = group_texts(mixed_spacing_page(), grouping);
+        assert_eq!(texts, ["T I T L E", "let x = 1;"]);
+
+        let grouping = TextGrouping {
+            gap_threshold: Some(8.0),
+            ..TextGrouping::default()
+        };
+        let texts = group_texts(mixed_spacing_page(), grouping);
+        assert_eq!(texts, ["TITLE", "letx=1;"]);
+    }
+
+    #[test]
+    fn group_chars_starts_a_new_group_past_the_y_band_tolerance() {
+        // 10pt text, a char raised by 2pt stays on the line while one raised by 4pt starts a new group
+        let mut chars = line_chars("ab", "Times-Roman", 10.0, 100.0, 5.0, 5.0);
+        chars.extend(line_chars("  c", "Times-Roman", 10.0, 102.0, 5.0, 5.0));
+        chars.extend(line_chars("   d", "Times-Roman", 10.0, 106.0, 5.0, 5.0));
+        let texts = group_texts(chars, TextGrouping::default());
+        assert_eq!(texts, ["abc", "d"]);
+    }
+
+    #[test]
+    fn group_chars_gives_every_line_its_own_group() {
+        // three 12pt lines, the second switching to a bold font halfway
+        let mut chars = line_chars("first line", "Times-Roman", 12.0, 150.0, 5.0, 6.0);
+        chars.extend(line_chars("second", "Times-Roman", 12.0, 135.0, 5.0, 6.0));
+        chars.extend(line_chars(
+            "       bold",
+            "Times-Bold",
+            12.0,
+            135.0,
+            5.0,
+            6.0,
+        ));
+        chars.extend(line_chars(
+            "third line",
+            "Times-Roman",
+            12.0,
+            120.0,
+            5.0,
+            6.0,
+        ));
+        let grouping = TextGrouping {
+            granularity: Granularity::Line,
+            ..TextGrouping::default()
+        };
+        let lines = group_chars(chars.clone(), &page_transform(), &grouping);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1].text, "second bold");
+        assert_eq!(lines[1].lx_pos.len(), "second bold".len());
+
+        let runs = group_texts(chars, TextGrouping::default());
+        assert_eq!(runs, ["first line", "second", "bold", "third line"]);
+    }
+
+    #[test]
+    fn group_chars_splits_words_on_the_gap_threshold() {
+        let grouping = TextGrouping {
+            granularity: Granularity::Word,
+            ..TextGrouping::default()
+        };
+        let texts = group_texts(mixed_spacing_page(), grouping);
+        assert_eq!(texts, ["TITLE", "let", "x", "=", "1;"]);
+    }
+
+    #[test]
+    fn is_unprintable_filters_nul_and_form_feed() {
+        assert!(is_unprintable('\0'));
+        assert!(is_unprintable('\u{000C}'));
+        assert!(is_unprintable('\r'));
+        assert!(is_unprintable('\n'));
+        assert!(is_unprintable('\u{007F}'));
+    }
+
+    #[test]
+    fn is_unprintable_keeps_slashes_and_tabs() {
+        assert!(!is_unprintable('/'));
+        assert!(!is_unprintable('\\'));
+        assert!(!is_unprintable('\t'));
+    }
+
+    #[test]
+    fn group_chars_drops_nul_and_form_feed_but_keeps_slashes() {
+        let mut chars = line_chars("usr/bin/env", "Courier", 10.0, 100.0, 5.0, 5.0);
+        // control chars drawn right after the slashes, where they don't leave a gap
+        for (index, text) in [(8, "\u{000C}"), (4, "\0")] {
+            let control_char = PageChar {
+                text: text.to_string(),
+                ..chars[index - 1].clone()
+            };
+            chars.insert(index, control_char);
+        }
+        assert_eq!(chars.len(), 13);
+        assert_eq!(group_texts(chars, TextGrouping::default()), ["usr/bin/env"]);
+    }
+
+    #[test]
+    fn is_unprintable_keeps_surrogate_pairs() {
+        // a character outside the basic multilingual plane, two utf-16 units in the pdf
+        let decoded = String::from_utf16(&[0xD83D, 0xDE00]).unwrap();
+        assert_eq!(decoded, "\u{1F600}");
+        assert!(!decoded.chars().any(is_unprintable));
+    }
+
+    #[test]
+    fn is_unprintable_keeps_lone_surrogates_decoded_lossily() {
+        // a lone surrogate can't be a char, decoding turns it into the replacement character
+        let decoded = String::from_utf16_lossy(&[0x0041, 0xD800, 0x0042]);
+        assert_eq!(decoded, "A\u{FFFD}B");
+        assert!(!decoded.chars().any(is_unprintable));
+    }
+
+    #[test]
+    fn granularity_parse_accepts_every_granularity() {
+        assert!(Granularity::parse("char") == Ok(Granularity::Char));
+        assert!(Granularity::parse("word") == Ok(Granularity::Word));
+        assert!(Granularity::parse(" Run ") == Ok(Granularity::Run));
+        assert!(Granularity::parse("LINE") == Ok(Granularity::Line));
+    }
+
+    #[test]
+    fn granularity_parse_rejects_unknown_granularities() {
+        assert!(Granularity::parse("paragraph").is_err());
+        assert!(Granularity::parse("").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+// streams the zip archives the api returns, written by the zip crate. The entries are stored without
+// compression since they are PDFs and images that are compressed already, and the bytes of every entry
+// are handed out once it's finished so archives can be sent while they are still being written
+use crate::error::ApiError;
+use ::zip::write::SimpleFileOptions;
+use ::zip::CompressionMethod;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// the zip crate writes the header of an entry before its data and seeks back to fill in the size
+// and crc once the data is written. This keeps the bytes from the start of the unfinished entry on,
+// everything before it is final and can be taken out
+#[derive(Default)]
+struct Buffer {
+    // bytes not taken out yet, they start at `offset` in the archive
+    buffer: Vec<u8>,
+    offset: u64,
+    position: u64,
+    // how much of `buffer` the zip crate is done with, as of its last flush
+    finished: usize,
+}
+
+impl Buffer {
+    fn take_finished(&mut self) -> Vec<u8> {
+        let rest = self.buffer.split_off(self.finished);
+        let finished = std::mem::replace(&mut self.buffer, rest);
+        self.offset += finished.len() as u64;
+        self.finished = 0;
+        finished
+    }
+
+    fn take_all(&mut self) -> Vec<u8> {
+        self.finished = self.buffer.len();
+        self.take_finished()
+    }
+}
+
+// the zip crate owns its writer until the archive is finished, this is a handle on the buffer
+// shared with ZipWriter so it can take out the finished bytes as it goes
+#[derive(Clone, Default)]
+struct StreamBuffer(Arc<Mutex<Buffer>>);
+
+impl StreamBuffer {
+    fn lock(&self) -> MutexGuard<'_, Buffer> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Write for StreamBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.lock().write(data)
+    }
+
+    // the zip crate flushes after finishing every entry (see set_flush_on_finish_file)
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl Seek for StreamBuffer {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.lock().seek(position)
+    }
+}
+
+// never used while writing, the zip crate only needs it to turn flushing on
+impl Read for StreamBuffer {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        self.lock().read(data)
+    }
+}
+
+impl Read for Buffer {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        let start = ((self.position - self.offset) as usize).min(self.buffer.len());
+        let read = (&self.buffer[start..]).read(data)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let start = (self.position - self.offset) as usize;
+        let end = start + data.len();
+        if end > self.buffer.len() {
+            self.buffer.resize(end, 0);
+        }
+        self.buffer[start..end].copy_from_slice(data);
+        self.position += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.finished = (self.position - self.offset) as usize;
+        Ok(())
+    }
+}
+
+impl Seek for Buffer {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let end = self.offset + self.buffer.len() as u64;
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => end.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        match position {
+            Some(position) if (self.offset..=end).contains(&position) => {
+                self.position = position;
+                Ok(position)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seeking into bytes of the zip archive already sent",
+            )),
+        }
+    }
+}
+
+pub struct ZipWriter {
+    writer: ::zip::ZipWriter<StreamBuffer>,
+    buffer: StreamBuffer,
+}
+
+impl Default for ZipWriter {
+    fn default() -> Self {
+        let buffer = StreamBuffer::default();
+        let mut writer = ::zip::ZipWriter::new(buffer.clone());
+        writer.set_flush_on_finish_file(true);
+        ZipWriter { writer, buffer }
+    }
+}
+
+fn zip_error(err: impl std::fmt::Display) -> ApiError {
+    ApiError::Internal(format!("failed to write the zip archive: {}", err))
+}
+
+impl ZipWriter {
+    // adds an entry and returns the bytes of the archive that are final, they have to be sent in
+    // order. The entry itself is only finished by the next one, or by finish
+    pub fn entry(&mut self, name: &str, data: &[u8]) -> Result<Vec<u8>, ApiError> {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(data.len() as u64 >= u32::MAX as u64);
+        self.writer.start_file(name, options).map_err(zip_error)?;
+        self.writer.write_all(data).map_err(zip_error)?;
+        Ok(self.buffer.lock().take_finished())
+    }
+
+    // returns the rest of the archive, its last entry and the central directory
+    pub fn finish(self) -> Result<Vec<u8>, ApiError> {
+        let buffer = self.writer.finish().map_err(zip_error)?;
+        let bytes = buffer.lock().take_all();
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::zip::ZipArchive;
+    use std::io::{Cursor, Read};
+
+    fn write_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::default();
+        let mut archive = Vec::new();
+        for (name, data) in entries {
+            archive.extend(zip.entry(name, data).unwrap());
+        }
+        archive.extend(zip.finish().unwrap());
+        archive
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"></svg>".repeat(100);
+        let png = (0..=255u8).cycle().take(70_000).collect::<Vec<u8>>();
+        let archive = write_archive(&[
+            ("page-0.svg", svg.as_bytes()),
+            ("page-0-1.5.png", &png),
+            ("empty.txt", b""),
+            ("page-1-été.svg", b"<svg/>"),
+        ]);
+
+        let mut archive = ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(archive.len(), 4);
+        for (index, (name, data)) in [
+            ("page-0.svg", svg.as_bytes()),
+            ("page-0-1.5.png", &png[..]),
+            ("empty.txt", b""),
+            ("page-1-été.svg", b"<svg/>"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut file = archive.by_index(index).unwrap();
+            assert_eq!(file.name(), name);
+            assert_eq!(file.compression(), CompressionMethod::Stored);
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, data);
+        }
+    }
+
+    #[test]
+    fn an_archive_without_entries_is_readable() {
+        let archive = ZipArchive::new(Cursor::new(write_archive(&[]))).unwrap();
+        assert!(archive.is_empty());
+    }
+
+    #[test]
+    fn finished_entries_are_handed_out_before_the_archive_is() {
+        let mut zip = ZipWriter::default();
+        // the first entry is only finished once the second one starts
+        assert!(zip.entry("page-0.svg", b"<svg/>").unwrap().is_empty());
+        let first = zip.entry("page-1.svg", b"<svg/>").unwrap();
+        assert!(first.starts_with(b"PK\x03\x04"));
+        assert!(first.ends_with(b"page-0.svg<svg/>"));
+
+        let mut archive = first;
+        archive.extend(zip.finish().unwrap());
+        let mut archive = ZipArchive::new(Cursor::new(archive)).unwrap();
+        let mut contents = String::new();
+        archive
+            .by_name("page-1.svg")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "<svg/>");
+    }
+}